@@ -1,19 +1,26 @@
 use core::fmt;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
+pub mod data;
 pub mod lua;
+pub mod message;
 pub mod meta;
+pub mod process;
+pub mod stats;
 pub mod wasm;
 
+pub use message::*;
 pub use meta::*;
+use serde::{Deserialize, Serialize};
+pub use stats::*;
 
 use crate::{
     color::Color,
     math_utils::{self, Point},
-    settings,
 };
 
-#[derive(PartialEq, Eq, Hash, Clone, Debug, Copy)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Debug, Copy, Serialize, Deserialize)]
 pub struct Id(pub uuid::Uuid);
 
 impl From<uuid::Uuid> for Id {
@@ -31,10 +38,12 @@ impl fmt::Display for Id {
 pub struct Intent {
     pub direction: MovementDirection,
     pub distance: f32,
-    pub attack: bool,
+    pub attack: Option<AttackKind>,
     pub turn_angle: f32,
     pub turn_head_angle: f32,
     pub turn_arms_angle: f32,
+    /// Messages to broadcast at the beginning of the next tick
+    pub messages: Vec<Message>,
 }
 
 impl Default for Intent {
@@ -44,8 +53,9 @@ impl Default for Intent {
             distance: 0.0,
             turn_head_angle: 0.0,
             turn_arms_angle: 0.0,
-            attack: false,
+            attack: None,
             turn_angle: 0.0,
+            messages: vec![],
         }
     }
 }
@@ -53,6 +63,15 @@ impl Default for Intent {
 pub struct Character {
     pub implementation: Box<dyn Impl>,
     pub intent: Intent,
+    /// Turns skipped for exceeding the budget, over the whole battle
+    pub skipped_turns: u32,
+    /// Whether the last turn was skipped, which the character is told about
+    /// on its next turn
+    pub turn_skipped: bool,
+    pub memory_exceeded: bool,
+    pub disqualified: bool,
+    /// Whether the character crashed and has to sit out the rest of the round
+    pub crashed: bool,
 }
 
 impl Character {
@@ -60,11 +79,87 @@ impl Character {
         Self {
             implementation,
             intent: Default::default(),
+            skipped_turns: 0,
+            turn_skipped: false,
+            memory_exceeded: false,
+            disqualified: false,
+            crashed: false,
+        }
+    }
+
+    /// Why the character has to leave the battle, if it has to
+    pub fn disqualification_reason(&self, max_skipped_turns: u32) -> Option<&'static str> {
+        if self.memory_exceeded {
+            Some("exceeding the memory limit")
+        } else if self.skipped_turns > max_skipped_turns {
+            Some("exceeding the budget too often")
+        } else {
+            None
         }
     }
 }
 
-#[derive(Debug)]
+/// Limits for running a character's code
+#[derive(Debug, Clone)]
+pub struct LoadOptions {
+    /// The number of Lua instructions per turn
+    pub instruction_limit: u64,
+    /// The amount of wasm fuel per turn. Unlike a Lua character exceeding its
+    /// budget, a wasm character running out of fuel loses its state, see
+    /// [`wasm::WasmImpl`]
+    pub fuel_limit: u64,
+    /// In bytes, for the Lua state or every linear memory of a wasm instance
+    pub memory_limit: usize,
+    /// A directory of Lua modules shared by all characters
+    pub lua_library: Option<PathBuf>,
+    /// Where the characters' data directories are kept, see [`data::DataDir`]
+    pub data_dir: Option<PathBuf>,
+    /// In bytes, per character. Soft for wasm characters, see
+    /// [`data::DataDir`]
+    pub data_quota: u64,
+    pub lua_sandbox: lua::LuaSandbox,
+    /// The character's own random seed, derived from the battle's seed
+    pub seed: u64,
+    pub wasi_grants: Vec<wasm::WasiGrant>,
+    /// The time a process character may take to answer all events of a turn
+    pub process_time_limit: Duration,
+}
+
+impl Default for LoadOptions {
+    fn default() -> Self {
+        Self {
+            instruction_limit: 1_000_000,
+            fuel_limit: 10_000_000,
+            memory_limit: 64 * 1024 * 1024,
+            lua_library: None,
+            data_dir: None,
+            data_quota: 1024 * 1024,
+            lua_sandbox: lua::LuaSandbox::Standard,
+            seed: 0,
+            wasi_grants: vec![],
+            process_time_limit: Duration::from_millis(100),
+        }
+    }
+}
+
+/// Everything a character learns about another character it sees
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SeenCharacter {
+    /// Tells apart multiple instances of the same character, see
+    /// [`Meta::unique_id`]
+    pub id: String,
+    pub name: String,
+    pub pos: Point,
+    pub heading: f32,
+    /// The distance moved during the last tick
+    pub velocity: Point,
+    pub distance: f32,
+    /// Relative to the observer's heading
+    pub bearing: f32,
+    pub hp: f32,
+}
+
+#[derive(Debug, Serialize)]
 pub struct CurrentCharacterState {
     pub x: f32,
     pub y: f32,
@@ -99,14 +194,71 @@ impl CurrentCharacterState {
 pub enum Event {
     Tick(u32, CurrentCharacterState),
     RoundStarted(u16),
-    RoundEnded(Option<Meta>),
+    RoundEnded(Option<Winner>),
     RoundDrawn,
     RoundWon,
-    EnemySeen(String, Point),
+    EnemySeen(SeenCharacter),
+    AllySeen(SeenCharacter),
+    /// A message broadcast by the named character
+    Message(String, Message),
     Death,
     EnemyDied(String),
     HitBy(Meta),
     AttackHit(Meta, Point),
+    Collision(Meta),
+    /// The side of the arena that was hit, and its bearing relative to the
+    /// character's heading
+    HitWall(WallSide, f32),
+    /// Something was heard at the given coarse bearing relative to the
+    /// character's heading
+    Sound(SoundKind, f32),
+    /// The character's last turn was skipped because it exceeded its budget
+    SkippedTurn,
+}
+
+/// The winner of a round: either a single character or a whole team
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Winner {
+    Character(Meta),
+    Team(String),
+}
+
+impl Winner {
+    pub fn includes(&self, meta: &Meta) -> bool {
+        match self {
+            Winner::Character(winner) => winner == meta,
+            Winner::Team(team) => meta.team.as_ref() == Some(team),
+        }
+    }
+
+    /// The name of the character or team, as passed to the characters.
+    pub fn name(&self) -> &str {
+        match self {
+            Winner::Character(meta) => &meta.name,
+            Winner::Team(team) => team,
+        }
+    }
+}
+
+/// The character itself if it fights alone, or its team
+impl From<&Meta> for Winner {
+    fn from(meta: &Meta) -> Self {
+        match &meta.team {
+            Some(team) => Winner::Team(team.clone()),
+            None => Winner::Character(meta.clone()),
+        }
+    }
+}
+
+impl fmt::Display for Winner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Winner::Character(meta) => {
+                write!(f, "Character {} (ID {})", meta.display_name(), meta.id)
+            }
+            Winner::Team(team) => write!(f, "Team {team}"),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -117,23 +269,71 @@ pub enum MovementDirection {
     Right,
 }
 
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum WallSide {
+    Top,
+    Right,
+    Bottom,
+    Left,
+}
+
+impl WallSide {
+    /// The absolute angle pointing from the inside of the arena towards the
+    /// wall
+    pub fn angle(&self) -> f32 {
+        match self {
+            WallSide::Top => 0.0,
+            WallSide::Right => math_utils::HALF_PI,
+            WallSide::Bottom => math_utils::PI,
+            WallSide::Left => 3.0 * math_utils::HALF_PI,
+        }
+    }
+}
+
+/// What a character can hear within the hearing radius
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SoundKind {
+    /// An enemy performing an attack
+    Attack,
+    /// An enemy moving
+    Movement,
+}
+
+/// The different attacks a character can perform. How much damage they deal,
+/// how fast and far they fly, and how long the character has to wait
+/// afterwards is defined by the rules.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AttackKind {
+    #[default]
+    Normal,
+    /// A fast but weak shot
+    Fast,
+    /// A slow shot dealing lots of damage
+    Heavy,
+    /// A short-range swing
+    Melee,
+}
+
 #[derive(PartialEq, Debug)]
 pub enum Command {
     Move(MovementDirection, f32),
-    Attack,
+    Attack(AttackKind),
     Turn(f32),
     TurnHead(f32),
     TurnArms(f32),
+    Broadcast(Message),
 }
 
 impl Command {
     pub fn index(&self) -> i32 {
         match self {
             Command::Move(_, _) => 0,
-            Command::Attack => 1,
+            Command::Attack(_) => 1,
             Command::Turn(_) => 2,
             Command::TurnHead(_) => 3,
             Command::TurnArms(_) => 4,
+            Command::Broadcast(_) => 5,
         }
     }
 }
@@ -154,8 +354,20 @@ impl From<Vec<Command>> for Commands {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventErrorKind {
+    /// The character ran out of instructions or fuel for this turn
+    BudgetExceeded,
+    /// The character tried to use more memory than allowed
+    MemoryExceeded,
+    /// A handler returned something that isn't a valid command
+    InvalidCommand,
+    Other,
+}
+
 #[derive(Debug)]
 pub struct EventError {
+    pub kind: EventErrorKind,
     pub message: String,
 }
 
@@ -166,13 +378,15 @@ impl fmt::Display for EventError {
 }
 
 pub trait Impl {
-    fn on_event(&mut self, event: &Event) -> Result<Commands, EventError>;
-}
+    /// Called at the start of every tick, before any events are dispatched.
+    fn start_turn(&mut self, _tick: u32) -> Result<(), EventError> {
+        Ok(())
+    }
 
-pub struct Stats {
-    pub rounds_won: u32,
+    fn on_event(&mut self, event: &Event) -> Result<Commands, EventError>;
 }
 
+#[derive(Clone)]
 pub struct State {
     pub hp: f32,
     pub pos: Point,
@@ -184,21 +398,21 @@ pub struct State {
 }
 
 impl State {
-    pub fn new() -> Self {
+    pub fn new(initial_hp: f32) -> Self {
         Self {
-            hp: (settings::INITIAL_HP),
+            hp: initial_hp,
             pos: Point::zero(),
             heading: 0.0,
             head_heading: 0.0,
             arms_heading: 0.0,
             attack_cooldown: 0,
-            stats: Stats { rounds_won: 0 },
+            stats: Stats::default(),
         }
     }
 
     // TODO: also randomize headings?
-    pub fn reset(&mut self, next_pos: Point) {
-        self.hp = settings::INITIAL_HP;
+    pub fn reset(&mut self, next_pos: Point, initial_hp: f32) {
+        self.hp = initial_hp;
         self.heading = 0.0;
         self.head_heading = 0.0;
         self.arms_heading = 0.0;
@@ -213,6 +427,15 @@ impl State {
         math_utils::normalize_absolute_angle(self.heading + self.arms_heading)
     }
 
+    /// Applies the damage and returns how much of it actually counted, which
+    /// is at most the HP that were left.
+    pub fn take_damage(&mut self, damage: f32) -> f32 {
+        let effective = damage.min(self.hp.max(0.0));
+        self.hp -= damage;
+        self.stats.damage_taken += effective;
+        effective
+    }
+
     pub fn alive(&self) -> bool {
         self.hp > 0.0
     }
@@ -0,0 +1,157 @@
+use core::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::Id;
+
+#[derive(Debug)]
+pub struct DataError(pub String);
+
+impl fmt::Display for DataError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl From<io::Error> for DataError {
+    fn from(value: io::Error) -> Self {
+        Self(value.to_string())
+    }
+}
+
+/// A directory in which a character keeps data between battles. It is shared
+/// by all instances of the character.
+///
+/// The quota is enforced by [`DataDir::write`]. Wasm characters write to the
+/// directory through WASI instead, so for them it is a soft quota: it is only
+/// checked between rounds, see [`DataDir::exceeded`].
+#[derive(Debug, Clone)]
+pub struct DataDir {
+    pub path: PathBuf,
+    /// In bytes, for all files together
+    pub quota: u64,
+}
+
+impl DataDir {
+    /// Opens the directory of the character with `id` below `root`, creating
+    /// it if needed.
+    pub fn open(root: &Path, id: &Id, quota: u64) -> io::Result<Self> {
+        let path = root.join(id.to_string());
+        std::fs::create_dir_all(&path)?;
+        Ok(Self { path, quota })
+    }
+
+    /// The combined size of all files in the directory
+    pub fn used(&self) -> io::Result<u64> {
+        fn dir_size(dir: &Path) -> io::Result<u64> {
+            let mut size = 0;
+            for entry in std::fs::read_dir(dir)? {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                size += if metadata.is_dir() {
+                    dir_size(&entry.path())?
+                } else {
+                    metadata.len()
+                };
+            }
+            Ok(size)
+        }
+        dir_size(&self.path)
+    }
+
+    /// Whether the files together are larger than the quota, which is
+    /// possible if they weren't written with [`DataDir::write`].
+    pub fn exceeded(&self) -> io::Result<bool> {
+        Ok(self.used()? > self.quota)
+    }
+
+    /// Only plain file names are allowed, so that nothing outside the
+    /// directory can be reached.
+    fn file(&self, name: &str) -> Result<PathBuf, DataError> {
+        let valid = !name.is_empty()
+            && !name.starts_with('.')
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+        if !valid {
+            return Err(DataError(format!("invalid data file name '{name}'")));
+        }
+        Ok(self.path.join(name))
+    }
+
+    /// The contents of the file `name`, or `None` if there is no such file.
+    pub fn read(&self, name: &str) -> Result<Option<Vec<u8>>, DataError> {
+        match std::fs::read(self.file(name)?) {
+            Ok(contents) => Ok(Some(contents)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Replaces the file `name` with `contents`, unless that would exceed the
+    /// quota.
+    pub fn write(&self, name: &str, contents: &[u8]) -> Result<(), DataError> {
+        let file = self.file(name)?;
+        let previous = match std::fs::metadata(&file) {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+        let used = self.used()?.saturating_sub(previous) + contents.len() as u64;
+        if used > self.quota {
+            return Err(DataError(format!(
+                "data quota exceeded, writing {name} would use {used} of {} bytes",
+                self.quota
+            )));
+        }
+        std::fs::write(file, contents)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod data_dir {
+        use super::*;
+        use crate::test_utils::TempDir;
+
+        fn data_dir(quota: u64) -> (TempDir, DataDir) {
+            let root = TempDir::new();
+            let data = DataDir::open(root.path(), &Id(uuid::Uuid::now_v7()), quota).unwrap();
+            (root, data)
+        }
+
+        #[test]
+        fn written_data_can_be_read_back() {
+            let (_root, data) = data_dir(100);
+            assert_eq!(data.read("profiles").unwrap(), None);
+            data.write("profiles", b"Kai: aggressive").unwrap();
+            assert_eq!(
+                data.read("profiles").unwrap().unwrap(),
+                b"Kai: aggressive".to_vec()
+            );
+        }
+
+        #[test]
+        fn quota_counts_all_files() {
+            let (_root, data) = data_dir(10);
+            data.write("a", b"123456").unwrap();
+            assert!(data.write("b", b"12345").is_err());
+            data.write("b", b"1234").unwrap();
+            // Overwriting only counts the new size
+            data.write("a", b"12").unwrap();
+            assert_eq!(data.used().unwrap(), 6);
+        }
+
+        #[test]
+        fn names_cannot_leave_the_directory() {
+            let (_root, data) = data_dir(100);
+            for name in ["", "../x", "/etc/passwd", "a/b", ".."] {
+                assert!(data.write(name, b"x").is_err(), "{name}");
+                assert!(data.read(name).is_err(), "{name}");
+            }
+        }
+    }
+}
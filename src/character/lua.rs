@@ -1,7 +1,13 @@
-use std::path::Path;
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 
+use clap::ValueEnum;
 use mlua::prelude::*;
+use serde::{Deserialize, Serialize};
 
+use super::data::DataDir;
 use super::{meta, *};
 use crate::color::Color;
 use crate::math_utils::{self, Point};
@@ -38,6 +44,21 @@ impl<'a> IntoLua<'a> for &CurrentCharacterState {
     }
 }
 
+impl<'a> IntoLua<'a> for &SeenCharacter {
+    fn into_lua(self, lua: &'a Lua) -> LuaResult<LuaValue<'a>> {
+        let t = lua.create_table()?;
+        t.set("id", self.id.as_str())?;
+        t.set("name", self.name.as_str())?;
+        t.set("pos", self.pos.clone())?;
+        t.set("heading", self.heading)?;
+        t.set("velocity", self.velocity.clone())?;
+        t.set("distance", self.distance)?;
+        t.set("bearing", self.bearing)?;
+        t.set("hp", self.hp)?;
+        Ok(LuaValue::Table(t))
+    }
+}
+
 impl<'a> FromLua<'a> for Id {
     fn from_lua(value: LuaValue<'a>, _lua: &'a Lua) -> LuaResult<Self> {
         match value {
@@ -62,24 +83,42 @@ impl<'a> FromLua<'a> for Id {
 
 impl<'a> FromLua<'a> for MovementDirection {
     fn from_lua(value: LuaValue<'a>, _lua: &'a Lua) -> LuaResult<Self> {
-        match value {
+        match &value {
             LuaValue::String(s) => match s.to_str()? {
                 "forward" => Ok(MovementDirection::Forward),
                 "backward" => Ok(MovementDirection::Backward),
                 "left" => Ok(MovementDirection::Left),
                 "right" => Ok(MovementDirection::Right),
-                // FIXME: implement and test this error case
-                other => todo!("invalid direction: {other}"),
+                _ => Err(invalid_direction(&value)),
             },
-            _ => Err(mlua::Error::FromLuaConversionError {
-                from: value.type_name(),
-                to: "MovementDirection",
-                message: Some("expected valid direction".to_string()),
-            }),
+            _ => Err(invalid_direction(&value)),
         }
     }
 }
 
+fn invalid_direction(value: &LuaValue) -> mlua::Error {
+    mlua::Error::FromLuaConversionError {
+        from: value.type_name(),
+        to: "MovementDirection",
+        message: Some(format!(
+            "expected one of 'forward', 'backward', 'left' or 'right', got {}",
+            describe(value)
+        )),
+    }
+}
+
+/// A short description of a value coming from Lua, for error messages
+fn describe(value: &LuaValue) -> String {
+    match value {
+        LuaValue::Nil => "nil".to_string(),
+        LuaValue::Boolean(b) => b.to_string(),
+        LuaValue::Integer(n) => n.to_string(),
+        LuaValue::Number(n) => n.to_string(),
+        LuaValue::String(s) => format!("'{}'", s.to_string_lossy()),
+        _ => value.type_name().to_string(),
+    }
+}
+
 impl<'a> IntoLua<'a> for MovementDirection {
     fn into_lua(self, lua: &'a Lua) -> LuaResult<LuaValue<'a>> {
         let s = match self {
@@ -92,30 +131,199 @@ impl<'a> IntoLua<'a> for MovementDirection {
     }
 }
 
-impl<'a> FromLua<'a> for Command {
+impl<'a> FromLua<'a> for AttackKind {
+    fn from_lua(value: LuaValue<'a>, _lua: &'a Lua) -> LuaResult<Self> {
+        let kind = match &value {
+            LuaValue::String(s) => match s.to_str()? {
+                "normal" => Some(AttackKind::Normal),
+                "fast" => Some(AttackKind::Fast),
+                "heavy" => Some(AttackKind::Heavy),
+                "melee" => Some(AttackKind::Melee),
+                _ => None,
+            },
+            _ => None,
+        };
+        kind.ok_or_else(|| mlua::Error::FromLuaConversionError {
+            from: value.type_name(),
+            to: "AttackKind",
+            message: Some(format!(
+                "expected one of 'normal', 'fast', 'heavy' or 'melee', got {}",
+                describe(&value)
+            )),
+        })
+    }
+}
+
+impl<'a> IntoLua<'a> for AttackKind {
+    fn into_lua(self, lua: &'a Lua) -> LuaResult<LuaValue<'a>> {
+        let s = match self {
+            AttackKind::Normal => "normal",
+            AttackKind::Fast => "fast",
+            AttackKind::Heavy => "heavy",
+            AttackKind::Melee => "melee",
+        };
+        s.into_lua(lua)
+    }
+}
+
+impl<'a> IntoLua<'a> for WallSide {
+    fn into_lua(self, lua: &'a Lua) -> LuaResult<LuaValue<'a>> {
+        let s = match self {
+            WallSide::Top => "top",
+            WallSide::Right => "right",
+            WallSide::Bottom => "bottom",
+            WallSide::Left => "left",
+        };
+        s.into_lua(lua)
+    }
+}
+
+impl<'a> IntoLua<'a> for SoundKind {
+    fn into_lua(self, lua: &'a Lua) -> LuaResult<LuaValue<'a>> {
+        let s = match self {
+            SoundKind::Attack => "attack",
+            SoundKind::Movement => "movement",
+        };
+        s.into_lua(lua)
+    }
+}
+
+impl<'a> FromLua<'a> for MessageValue {
+    fn from_lua(value: LuaValue<'a>, _lua: &'a Lua) -> LuaResult<Self> {
+        match value {
+            LuaValue::Boolean(b) => Ok(MessageValue::Boolean(b)),
+            LuaValue::Integer(n) => Ok(MessageValue::Integer(n)),
+            LuaValue::Number(n) => Ok(MessageValue::Number(n)),
+            LuaValue::String(s) => Ok(MessageValue::Text(s.to_str()?.to_string())),
+            _ => Err(mlua::Error::FromLuaConversionError {
+                from: value.type_name(),
+                to: "MessageValue",
+                message: Some("expected boolean, number or string".to_string()),
+            }),
+        }
+    }
+}
+
+impl<'a> IntoLua<'a> for MessageValue {
+    fn into_lua(self, lua: &'a Lua) -> LuaResult<LuaValue<'a>> {
+        match self {
+            MessageValue::Boolean(b) => b.into_lua(lua),
+            MessageValue::Integer(n) => n.into_lua(lua),
+            MessageValue::Number(n) => n.into_lua(lua),
+            MessageValue::Text(s) => s.into_lua(lua),
+        }
+    }
+}
+
+impl<'a> FromLua<'a> for Message {
     fn from_lua(value: LuaValue<'a>, _lua: &'a Lua) -> LuaResult<Self> {
         match value {
-            LuaValue::Table(t) => match t.get::<&str, String>("tag")?.as_str() {
-                "move" => {
-                    let dist = t.get("distance")?;
-                    let dir: MovementDirection = t.get("direction")?;
-                    Ok(Command::Move(dir, dist))
+            LuaValue::String(s) => Ok(Message::Text(s.to_str()?.to_string())),
+            LuaValue::Table(t) => {
+                let mut entries = BTreeMap::new();
+                for pair in t.pairs::<String, MessageValue>() {
+                    let (key, value) = pair?;
+                    entries.insert(key, value);
                 }
-                "attack" => Ok(Command::Attack),
-                "turn" => Ok(Command::Turn(t.get("angle")?)),
-                "turn_head" => Ok(Command::TurnHead(t.get("angle")?)),
-                "turn_arms" => Ok(Command::TurnArms(t.get("angle")?)),
-                s => todo!("invalid tag: {s}"),
-            },
+                Ok(Message::Table(entries))
+            }
             _ => Err(mlua::Error::FromLuaConversionError {
                 from: value.type_name(),
-                to: "CharacterCommand",
-                message: Some("expected valid character command".to_string()),
+                to: "Message",
+                message: Some("expected string or flat table".to_string()),
             }),
         }
     }
 }
 
+impl<'a> IntoLua<'a> for Message {
+    fn into_lua(self, lua: &'a Lua) -> LuaResult<LuaValue<'a>> {
+        match self {
+            Message::Text(s) => s.into_lua(lua),
+            Message::Table(entries) => {
+                let t = lua.create_table()?;
+                for (key, value) in entries {
+                    t.set(key, value)?;
+                }
+                Ok(LuaValue::Table(t))
+            }
+        }
+    }
+}
+
+fn invalid_command(value: &LuaValue, message: String) -> mlua::Error {
+    mlua::Error::FromLuaConversionError {
+        from: value.type_name(),
+        to: "Command",
+        message: Some(message),
+    }
+}
+
+/// Reads a number of a command, which has to be finite
+fn command_number(lua: &Lua, t: &LuaTable, tag: &str, key: &str) -> LuaResult<f32> {
+    let value: LuaValue = t.get(key)?;
+    match f32::from_lua(value.clone(), lua) {
+        Ok(n) if n.is_finite() => Ok(n),
+        _ => Err(invalid_command(
+            &value,
+            format!(
+                "'{key}' of '{tag}' must be a finite number, got {}",
+                describe(&value)
+            ),
+        )),
+    }
+}
+
+impl<'a> FromLua<'a> for Command {
+    fn from_lua(value: LuaValue<'a>, lua: &'a Lua) -> LuaResult<Self> {
+        let LuaValue::Table(t) = &value else {
+            return Err(invalid_command(
+                &value,
+                format!("expected a command table, got {}", describe(&value)),
+            ));
+        };
+        let tag: LuaValue = t.get("tag")?;
+        let LuaValue::String(tag) = &tag else {
+            return Err(invalid_command(
+                &value,
+                format!("expected a 'tag' string, got {}", describe(&tag)),
+            ));
+        };
+        match tag.to_str()? {
+            "move" => {
+                let dist = command_number(lua, t, "move", "distance")?;
+                if dist < 0.0 {
+                    return Err(invalid_command(
+                        &value,
+                        format!("'distance' of 'move' must not be negative, got {dist}"),
+                    ));
+                }
+                let dir: MovementDirection = t.get("direction")?;
+                Ok(Command::Move(dir, dist))
+            }
+            "attack" => {
+                let kind: Option<AttackKind> = t.get("kind")?;
+                Ok(Command::Attack(kind.unwrap_or_default()))
+            }
+            "turn" => Ok(Command::Turn(command_number(lua, t, "turn", "angle")?)),
+            "turn_head" => Ok(Command::TurnHead(command_number(
+                lua,
+                t,
+                "turn_head",
+                "angle",
+            )?)),
+            "turn_arms" => Ok(Command::TurnArms(command_number(
+                lua,
+                t,
+                "turn_arms",
+                "angle",
+            )?)),
+            "broadcast" => Ok(Command::Broadcast(t.get("message")?)),
+            s => Err(invalid_command(&value, format!("unknown tag '{s}'"))),
+        }
+    }
+}
+
 impl<'a> FromLua<'a> for Commands {
     fn from_lua(value: LuaValue<'a>, lua: &'a Lua) -> LuaResult<Self> {
         match value {
@@ -134,8 +342,9 @@ fn create_tagged_table<'a>(lua: &'a Lua, tag: &str) -> LuaResult<LuaTable<'a>> {
 impl<'a> IntoLua<'a> for Command {
     fn into_lua(self, lua: &'a Lua) -> LuaResult<LuaValue<'a>> {
         match self {
-            Command::Attack => {
+            Command::Attack(kind) => {
                 let t = create_tagged_table(&lua, "attack")?;
+                t.set("kind", kind)?;
                 Ok(LuaValue::Table(t))
             }
             Command::Turn(angle) => {
@@ -159,6 +368,11 @@ impl<'a> IntoLua<'a> for Command {
                 t.set("direction", dir)?;
                 Ok(LuaValue::Table(t))
             }
+            Command::Broadcast(message) => {
+                let t = create_tagged_table(&lua, "broadcast")?;
+                t.set("message", message)?;
+                Ok(LuaValue::Table(t))
+            }
         }
     }
 }
@@ -175,29 +389,230 @@ impl<'a> FromLua<'a> for Color {
             _ => Err(mlua::Error::FromLuaConversionError {
                 from: value.type_name(),
                 to: "Color",
-                message: Some("expected valid direction".to_string()),
+                message: Some("expected table with red, green and blue".to_string()),
             }),
         }
     }
 }
 
+/// Which parts of Lua's standard library characters get
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LuaSandbox {
+    /// All the safe libraries, including `io` and `os`
+    Standard,
+    /// No file or process access, and characters behave the same on every
+    /// machine: `os.time` and `os.clock` tell the tick, and `math.random` is
+    /// seeded from the battle's seed. Only the order of `pairs` and `next` is
+    /// not deterministic, as Lua seeds its string hashes randomly. `load`
+    /// only accepts source code, not precompiled chunks
+    Strict,
+}
+
+const RANDOMSEED: &str = "luarena_randomseed";
+const LOAD: &str = "luarena_load";
+
+fn register_strict_sandbox(lua: &Lua, tick: Rc<Cell<u32>>, seed: u64) -> LuaResult<()> {
+    let os = lua.create_table()?;
+    let clock_tick = Rc::clone(&tick);
+    os.set("time", lua.create_function(move |_, ()| Ok(tick.get()))?)?;
+    os.set(
+        "clock",
+        lua.create_function(move |_, ()| Ok(clock_tick.get() as f64))?,
+    )?;
+    lua.globals().set("os", os)?;
+    // Both read files, and like `load` would accept precompiled chunks
+    lua.globals().set("loadfile", LuaNil)?;
+    lua.globals().set("dofile", LuaNil)?;
+
+    // Precompiled chunks are not checked by Lua and can crash the process,
+    // so `load` always gets mode "t"
+    let load: LuaFunction = lua.globals().get("load")?;
+    lua.set_named_registry_value(LOAD, load)?;
+    lua.globals().set(
+        "load",
+        lua.create_function(|lua, args: LuaMultiValue| {
+            let load: LuaFunction = lua.named_registry_value(LOAD)?;
+            let mut args = args.into_vec();
+            // Only an absent `env` means the globals, a given `nil` doesn't
+            if args.len() < 3 {
+                args.resize(3, LuaNil);
+            }
+            args[2] = LuaValue::String(lua.create_string("t")?);
+            load.call::<_, LuaMultiValue>(LuaMultiValue::from_vec(args))
+        })?,
+    )?;
+
+    // Without arguments, `math.randomseed` would seed from the time
+    let math: LuaTable = lua.globals().get("math")?;
+    let randomseed: LuaFunction = math.get("randomseed")?;
+    randomseed.call::<_, ()>(seed as i64)?;
+    lua.set_named_registry_value(RANDOMSEED, randomseed)?;
+    math.set(
+        "randomseed",
+        lua.create_function(move |lua, args: LuaMultiValue| {
+            let randomseed: LuaFunction = lua.named_registry_value(RANDOMSEED)?;
+            if args.is_empty() {
+                randomseed.call::<_, ()>(seed as i64)
+            } else {
+                randomseed.call::<_, ()>(args)
+            }
+        })?,
+    )
+}
+
+/// How many instructions may pass between two checks of the budget
+const INSTRUCTION_CHECK_INTERVAL: u32 = 1000;
+
+struct InstructionBudget {
+    limit: u64,
+    used: Cell<u64>,
+    exceeded: Cell<bool>,
+}
+
+/// Counts the instructions in steps of `interval`. Once the budget is
+/// exceeded, the hook raises an error on every single instruction, so that a
+/// handler catching the errors with `pcall` in a loop can't keep running.
+fn set_budget_hook(lua: &Lua, budget: Rc<InstructionBudget>, interval: u32) {
+    lua.set_hook(
+        LuaHookTriggers::new().every_nth_instruction(interval),
+        move |lua, _| {
+            let used = budget.used.get() + interval as u64;
+            budget.used.set(used);
+            if used > budget.limit {
+                if !budget.exceeded.get() {
+                    budget.exceeded.set(true);
+                    set_budget_hook(lua, Rc::clone(&budget), 1);
+                }
+                return Err(LuaError::runtime("instruction budget exceeded"));
+            }
+            Ok(())
+        },
+    );
+}
+
 pub struct LuaImpl {
     lua: Lua,
     key: LuaRegistryKey,
+    budget: Rc<InstructionBudget>,
+    tick: Rc<Cell<u32>>,
+}
+
+/// Finds the file of the module `name` in one of `module_dirs`. Like in plain
+/// Lua, `a.b` is looked up as `a/b.lua` and then `a/b/init.lua`. Files
+/// outside of the directories, e.g. behind a symlink, are refused.
+fn find_module(name: &str, module_dirs: &[PathBuf]) -> Result<PathBuf, String> {
+    let valid_part = |part: &str| {
+        !part.is_empty()
+            && part
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    };
+    if !name.split('.').all(valid_part) {
+        return Err(format!("invalid module name '{name}'"));
+    }
+    let relative: PathBuf = name.split('.').collect();
+    for dir in module_dirs {
+        let Ok(root) = dir.canonicalize() else {
+            continue;
+        };
+        for candidate in [relative.with_extension("lua"), relative.join("init.lua")] {
+            let Ok(path) = root.join(candidate).canonicalize() else {
+                continue;
+            };
+            if !path.starts_with(&root) {
+                return Err(format!(
+                    "module '{name}' refused, {} is outside of {}",
+                    path.display(),
+                    root.display()
+                ));
+            }
+            if path.is_file() {
+                return Ok(path);
+            }
+        }
+    }
+    Err(format!("module '{name}' not found"))
+}
+
+const LOADED_MODULES: &str = "luarena_loaded_modules";
+
+/// Replaces `require` with one that only loads modules from `module_dirs`
+/// and removes every other way of loading code from files.
+fn register_require(lua: &Lua, module_dirs: Vec<PathBuf>) -> LuaResult<()> {
+    let globals = lua.globals();
+    globals.set("dofile", LuaNil)?;
+    globals.set("loadfile", LuaNil)?;
+    if let Some(package) = globals.get::<_, Option<LuaTable>>("package")? {
+        for key in ["loadlib", "searchpath", "searchers", "path", "cpath"] {
+            package.set(key, LuaNil)?;
+        }
+    }
+    lua.set_named_registry_value(LOADED_MODULES, lua.create_table()?)?;
+    let require = lua.create_function(move |lua, name: String| {
+        let loaded: LuaTable = lua.named_registry_value(LOADED_MODULES)?;
+        let module: LuaValue = loaded.get(name.as_str())?;
+        if module != LuaNil {
+            return Ok(module);
+        }
+        let path = find_module(&name, &module_dirs).map_err(LuaError::runtime)?;
+        let code = std::fs::read_to_string(&path)?;
+        let chunk_name = format!(
+            "@{}",
+            path.file_name().unwrap_or_default().to_string_lossy()
+        );
+        let module = match lua
+            .load(code)
+            .set_name(chunk_name)
+            .call::<_, LuaValue>(name.as_str())?
+        {
+            LuaNil => LuaValue::Boolean(true),
+            module => module,
+        };
+        loaded.set(name, module.clone())?;
+        Ok(module)
+    })?;
+    globals.set("require", require)
 }
 
 impl LuaImpl {
-    pub fn new(code: &str) -> LuaResult<Self> {
-        let lua = Lua::new();
-        lua.load_from_std_lib(LuaStdLib::ALL_SAFE)?;
+    /// Besides the shared library, `require` finds the modules in
+    /// `module_dirs`.
+    pub fn new(
+        code: &str,
+        chunk_name: &str,
+        mut module_dirs: Vec<PathBuf>,
+        options: &LoadOptions,
+    ) -> LuaResult<Self> {
+        let libs = match options.lua_sandbox {
+            LuaSandbox::Standard => LuaStdLib::ALL_SAFE,
+            LuaSandbox::Strict => LuaStdLib::ALL_SAFE ^ (LuaStdLib::IO | LuaStdLib::OS),
+        };
+        let lua = Lua::new_with(libs, LuaOptions::new())?;
+        lua.set_memory_limit(options.memory_limit)?;
+        let tick = Rc::new(Cell::new(0));
+        if options.lua_sandbox == LuaSandbox::Strict {
+            register_strict_sandbox(&lua, Rc::clone(&tick), options.seed)?;
+        }
+        module_dirs.extend(options.lua_library.clone());
+        register_require(&lua, module_dirs)?;
+
+        let budget = Rc::new(InstructionBudget {
+            limit: options.instruction_limit,
+            used: Cell::new(0),
+            exceeded: Cell::new(false),
+        });
+        set_budget_hook(&lua, Rc::clone(&budget), INSTRUCTION_CHECK_INTERVAL);
 
         let table_key = {
-            let t: LuaTable = lua.load(code).eval()?;
+            let t: LuaTable = lua.load(code).set_name(chunk_name).eval()?;
             lua.create_registry_value(t)?
         };
         Ok(Self {
             lua,
             key: table_key,
+            budget,
+            tick,
         })
     }
 
@@ -211,15 +626,28 @@ impl LuaImpl {
         A: for<'a> IntoLuaMulti<'a>,
     {
         let t = self.table()?;
-        let res = if t.contains_key(name)? {
-            t.call_function(name, args)?
-        } else {
-            Commands::none()
-        };
-        Ok(res)
+        if !t.contains_key(name)? {
+            return Ok(Commands::none());
+        }
+        // A handler could catch the budget error with `pcall` and carry on,
+        // so the budget is checked on its own
+        match t.call_function(name, args) {
+            _ if self.budget.exceeded.get() => Err(EventError {
+                kind: EventErrorKind::BudgetExceeded,
+                message: format!("instruction budget exceeded in {name}"),
+            }),
+            Err(err) => match invalid_command_cause(&err) {
+                Some(cause) => Err(EventError {
+                    kind: EventErrorKind::InvalidCommand,
+                    message: format!("invalid command returned by {name}: {cause}"),
+                }),
+                None => Err(err.into()),
+            },
+            Ok(commands) => Ok(commands),
+        }
     }
 
-    fn register_lua_library(&self, meta: &meta::Meta) -> LuaResult<()> {
+    fn register_lua_library(&self, meta: &meta::Meta, data: Option<DataDir>) -> LuaResult<()> {
         let lua = &self.lua;
         let name = meta.display_name();
         let mut me = lua.create_table()?;
@@ -232,31 +660,56 @@ impl LuaImpl {
             })?,
         )?;
         register_commands(&mut me, lua)?;
+        register_data(&mut me, lua, data)?;
         lua.globals().set("me", me)?;
         register_utils(lua)?;
         Ok(())
     }
 
-    pub fn load(character_dir: &Path, meta: &meta::Meta) -> LuaResult<Self> {
+    pub fn load(character_dir: &Path, meta: &meta::Meta, options: &LoadOptions) -> LuaResult<Self> {
         let file = character_dir.join(&meta.entrypoint);
         let code = std::fs::read_to_string(file)?;
-        let res = Self::new(&code)?;
-        res.register_lua_library(meta)?;
+        let chunk_name = format!("@{}", meta.entrypoint.display());
+        let module_dirs = vec![character_dir.to_path_buf()];
+        let res = Self::new(&code, &chunk_name, module_dirs, options)?;
+        let data = match &options.data_dir {
+            Some(root) => Some(DataDir::open(root, &meta.id, options.data_quota)?),
+            None => None,
+        };
+        res.register_lua_library(meta, data)?;
         Ok(res)
     }
 }
 
 impl Impl for LuaImpl {
+    fn start_turn(&mut self, tick: u32) -> Result<(), EventError> {
+        self.tick.set(tick);
+        self.budget.used.set(0);
+        if self.budget.exceeded.replace(false) {
+            set_budget_hook(
+                &self.lua,
+                Rc::clone(&self.budget),
+                INSTRUCTION_CHECK_INTERVAL,
+            );
+        }
+        Ok(())
+    }
+
     fn on_event(&mut self, event: &Event) -> Result<Commands, EventError> {
         match event {
             Event::Tick(n, state) => self.call_event_handler("on_tick", (*n, state)),
             Event::RoundStarted(n) => self.call_event_handler("on_round_started", *n),
             Event::RoundEnded(opt_winner) => self.call_event_handler(
-                "on_round_started",
-                opt_winner.as_ref().map(|meta| meta.name.clone()),
+                "on_round_ended",
+                opt_winner.as_ref().map(|winner| winner.name().to_string()),
             ),
-            Event::EnemySeen(name, pos) => {
-                self.call_event_handler("on_enemy_seen", (name.to_string(), pos.clone()))
+            Event::EnemySeen(seen) => self
+                .call_event_handler("on_enemy_seen", (seen.name.clone(), seen.pos.clone(), seen)),
+            Event::AllySeen(seen) => {
+                self.call_event_handler("on_ally_seen", (seen.name.clone(), seen.pos.clone(), seen))
+            }
+            Event::Message(sender, message) => {
+                self.call_event_handler("on_message", (sender.to_string(), message.clone()))
             }
             Event::HitBy(meta) => self.call_event_handler("on_hit_by", meta.name.clone()),
             Event::AttackHit(meta, pos) => {
@@ -266,20 +719,51 @@ impl Impl for LuaImpl {
             Event::EnemyDied(deceased_meta) => {
                 self.call_event_handler("on_enemy_death", deceased_meta.to_string())
             }
+            Event::Collision(meta) => self.call_event_handler("on_collision", meta.name.clone()),
+            Event::HitWall(side, bearing) => {
+                self.call_event_handler("on_hit_wall", (*side, *bearing))
+            }
+            Event::Sound(kind, bearing) => self.call_event_handler("on_sound", (*kind, *bearing)),
             Event::RoundDrawn => self.call_event_handler("on_round_drawn", ()),
             Event::RoundWon => self.call_event_handler("on_round_won", ()),
+            Event::SkippedTurn => self.call_event_handler("on_skipped_turn", ()),
         }
     }
 }
 
 impl From<mlua::Error> for EventError {
     fn from(err: mlua::Error) -> Self {
+        if is_memory_error(&err) {
+            return Self {
+                kind: EventErrorKind::MemoryExceeded,
+                message: format!("memory limit exceeded ({err})"),
+            };
+        }
         Self {
+            kind: EventErrorKind::Other,
             message: format!("{err}"),
         }
     }
 }
 
+fn is_memory_error(err: &mlua::Error) -> bool {
+    match err {
+        mlua::Error::MemoryError(_) => true,
+        mlua::Error::CallbackError { cause, .. } => is_memory_error(cause),
+        _ => false,
+    }
+}
+
+/// Invalid arguments to the `me` helpers reach the handler wrapped in
+/// callback errors, just like invalid returned commands do.
+fn invalid_command_cause(err: &mlua::Error) -> Option<&mlua::Error> {
+    match err {
+        mlua::Error::FromLuaConversionError { .. } | mlua::Error::BadArgument { .. } => Some(err),
+        mlua::Error::CallbackError { cause, .. } => invalid_command_cause(cause),
+        _ => None,
+    }
+}
+
 fn register_commands(t: &mut LuaTable, lua: &Lua) -> LuaResult<()> {
     let move_ =
         lua.create_function(|_, dist: f32| Ok(Command::Move(MovementDirection::Forward, dist)))?;
@@ -297,7 +781,9 @@ fn register_commands(t: &mut LuaTable, lua: &Lua) -> LuaResult<()> {
         lua.create_function(|_, dist: f32| Ok(Command::Move(MovementDirection::Right, dist)))?;
     t.set("move_right", move_right)?;
 
-    let attack = lua.create_function(|_, _: ()| Ok(Command::Attack))?;
+    let attack = lua.create_function(|_, kind: Option<AttackKind>| {
+        Ok(Command::Attack(kind.unwrap_or_default()))
+    })?;
     t.set("attack", attack)?;
 
     let turn = lua.create_function(|_, angle: f32| Ok(Command::Turn(angle)))?;
@@ -309,6 +795,42 @@ fn register_commands(t: &mut LuaTable, lua: &Lua) -> LuaResult<()> {
     let turn_arms = lua.create_function(|_, angle: f32| Ok(Command::TurnArms(angle)))?;
     t.set("turn_arms", turn_arms)?;
 
+    let broadcast = lua.create_function(|_, message: Message| Ok(Command::Broadcast(message)))?;
+    t.set("broadcast", broadcast)?;
+
+    Ok(())
+}
+
+/// Like Lua's `io` functions, these return `nil` or `false` and a message
+/// when they fail.
+fn register_data(t: &mut LuaTable, lua: &Lua, data: Option<DataDir>) -> LuaResult<()> {
+    let data = Rc::new(data);
+    let read_data = {
+        let data = Rc::clone(&data);
+        lua.create_function(move |lua, name: String| {
+            let Some(data) = data.as_ref() else {
+                return (LuaNil, "no data directory").into_lua_multi(lua);
+            };
+            match data.read(&name) {
+                Ok(Some(contents)) => lua.create_string(contents)?.into_lua_multi(lua),
+                Ok(None) => LuaNil.into_lua_multi(lua),
+                Err(e) => (LuaNil, e.to_string()).into_lua_multi(lua),
+            }
+        })?
+    };
+    t.set("read_data", read_data)?;
+
+    let write_data = lua.create_function(move |lua, (name, contents): (String, LuaString)| {
+        let Some(data) = data.as_ref() else {
+            return (false, "no data directory").into_lua_multi(lua);
+        };
+        match data.write(&name, contents.as_bytes()) {
+            Ok(()) => true.into_lua_multi(lua),
+            Err(e) => (false, e.to_string()).into_lua_multi(lua),
+        }
+    })?;
+    t.set("write_data", write_data)?;
+
     Ok(())
 }
 
@@ -344,23 +866,478 @@ mod tests {
 
         #[test]
         fn lua_character_can_be_loaded_from_code() {
-            LuaImpl::new("return {}").expect("lua character could not be created");
+            LuaImpl::new("return {}", "=test", vec![], &LoadOptions::default())
+                .expect("lua character could not be created");
         }
 
         #[test]
         fn call_on_tick() {
-            let mut character = LuaImpl::new("return { on_round_started = function(n) return { { tag = \"move\", distance = 13.12, direction = \"left\" } } end }")
+            let mut character = LuaImpl::new("return { on_round_started = function(n) return { { tag = \"move\", distance = 13.12, direction = \"left\" } } end }", "=test", vec![], &LoadOptions::default())
                 .expect("lua character could not be created");
             let res: Commands = character.on_event(&Event::RoundStarted(17)).unwrap();
             let cmd = res.value.first().expect("some command");
             assert_eq!(*cmd, Command::Move(MovementDirection::Left, 13.12));
         }
 
+        #[test]
+        fn attack_kind_defaults_to_normal() {
+            let mut character = LuaImpl::new(
+                "return { on_round_started = function(n) return { { tag = \"attack\" }, { tag = \"attack\", kind = \"heavy\" } } end }", "=test", vec![], &LoadOptions::default()
+            )
+            .unwrap();
+            let res: Commands = character.on_event(&Event::RoundStarted(1)).unwrap();
+            assert_eq!(
+                res.value,
+                vec![
+                    Command::Attack(AttackKind::Normal),
+                    Command::Attack(AttackKind::Heavy)
+                ]
+            );
+        }
+
+        #[test]
+        fn unknown_attack_kinds_are_rejected() {
+            let mut character = LuaImpl::new(
+                "return { on_round_started = function(n) return { { tag = \"attack\", kind = \"laser\" } } end }", "=test", vec![], &LoadOptions::default()
+            )
+            .unwrap();
+            assert!(character.on_event(&Event::RoundStarted(1)).is_err());
+        }
+
+        fn invalid_command_error(commands: &str) -> EventError {
+            let code =
+                format!("return {{ on_round_started = function(n) return {{ {commands} }} end }}");
+            let mut character =
+                LuaImpl::new(&code, "=test", vec![], &LoadOptions::default()).unwrap();
+            let err = character.on_event(&Event::RoundStarted(1)).err().unwrap();
+            assert_eq!(err.kind, EventErrorKind::InvalidCommand);
+            assert!(err.message.contains("on_round_started"), "{}", err.message);
+            err
+        }
+
+        #[test]
+        fn unknown_tags_are_rejected() {
+            let err = invalid_command_error("{ tag = \"jump\" }");
+            assert!(err.message.contains("'jump'"), "{}", err.message);
+        }
+
+        #[test]
+        fn unknown_directions_are_rejected() {
+            let err = invalid_command_error("{ tag = \"move\", distance = 1, direction = \"up\" }");
+            assert!(err.message.contains("'up'"), "{}", err.message);
+        }
+
+        #[test]
+        fn commands_of_wrong_type_are_rejected() {
+            invalid_command_error("42");
+            invalid_command_error("{ tag = 1 }");
+            invalid_command_error("{ tag = \"turn\", angle = \"left\" }");
+        }
+
+        #[test]
+        fn non_finite_angles_are_rejected() {
+            let err = invalid_command_error("{ tag = \"turn\", angle = 0/0 }");
+            assert!(err.message.contains("NaN"), "{}", err.message);
+            invalid_command_error("{ tag = \"turn_head\", angle = math.huge }");
+            invalid_command_error("{ tag = \"turn_arms\", angle = -math.huge }");
+        }
+
+        #[test]
+        fn negative_distances_are_rejected() {
+            let err =
+                invalid_command_error("{ tag = \"move\", distance = -5, direction = \"forward\" }");
+            assert!(err.message.contains("-5"), "{}", err.message);
+        }
+
+        fn invalid_helper_call_error(call: &str) -> EventError {
+            let code =
+                format!("return {{ on_round_started = function(n) return {{ {call} }} end }}");
+            let mut character =
+                LuaImpl::new(&code, "=test", vec![], &LoadOptions::default()).unwrap();
+            let mut me = character.lua.create_table().unwrap();
+            register_commands(&mut me, &character.lua).unwrap();
+            character.lua.globals().set("me", me).unwrap();
+            let err = character.on_event(&Event::RoundStarted(1)).err().unwrap();
+            assert_eq!(err.kind, EventErrorKind::InvalidCommand);
+            assert!(err.message.contains("on_round_started"), "{}", err.message);
+            err
+        }
+
+        #[test]
+        fn helpers_reject_invalid_arguments() {
+            let err = invalid_helper_call_error("me.attack(\"laser\")");
+            assert!(err.message.contains("'laser'"), "{}", err.message);
+            invalid_helper_call_error("me.move(\"x\")");
+            invalid_helper_call_error("me.turn({})");
+            invalid_helper_call_error("me.broadcast({ pos = { x = 1 } })");
+        }
+
+        #[test]
+        fn broadcast_flat_table() {
+            let mut character = LuaImpl::new(
+                "return { on_round_started = function(n) return { { tag = \"broadcast\", message = { target = \"Kai\", x = 3 } } } end }", "=test", vec![], &LoadOptions::default()
+            )
+            .unwrap();
+            let res: Commands = character.on_event(&Event::RoundStarted(1)).unwrap();
+            let entries = BTreeMap::from([
+                ("target".to_string(), MessageValue::Text("Kai".to_string())),
+                ("x".to_string(), MessageValue::Integer(3)),
+            ]);
+            assert_eq!(res.value, vec![Command::Broadcast(Message::Table(entries))]);
+        }
+
+        #[test]
+        fn broadcast_rejects_nested_tables() {
+            let mut character = LuaImpl::new(
+                "return { on_round_started = function(n) return { { tag = \"broadcast\", message = { pos = { x = 1 } } } } end }", "=test", vec![], &LoadOptions::default()
+            )
+            .unwrap();
+            assert!(character.on_event(&Event::RoundStarted(1)).is_err());
+        }
+
+        #[test]
+        fn receives_messages() {
+            let mut character = LuaImpl::new(
+                "return { on_message = function(sender, msg) return { { tag = \"move\", distance = msg.x, direction = sender } } end }", "=test", vec![], &LoadOptions::default()
+            )
+            .unwrap();
+            let entries = BTreeMap::from([("x".to_string(), MessageValue::Number(4.0))]);
+            let res: Commands = character
+                .on_event(&Event::Message("left".to_string(), Message::Table(entries)))
+                .unwrap();
+            assert_eq!(res.value, vec![Command::Move(MovementDirection::Left, 4.0)]);
+        }
+
+        #[test]
+        fn receives_seen_character_info() {
+            let mut character = LuaImpl::new(
+                "return { on_enemy_seen = function(name, pos, info) return { { tag = \"turn\", angle = info.bearing + info.velocity.x + pos.x } } end }", "=test", vec![], &LoadOptions::default()
+            )
+            .unwrap();
+            let seen = SeenCharacter {
+                id: "some-id-2".to_string(),
+                name: "Kai".to_string(),
+                pos: Point { x: 10.0, y: 20.0 },
+                heading: 0.0,
+                velocity: Point { x: 1.0, y: 0.0 },
+                distance: 100.0,
+                bearing: 0.5,
+                hp: 80.0,
+            };
+            let res: Commands = character.on_event(&Event::EnemySeen(seen)).unwrap();
+            assert_eq!(res.value, vec![Command::Turn(11.5)]);
+        }
+
+        #[test]
+        fn receives_sounds() {
+            let mut character = LuaImpl::new(
+                "return { on_sound = function(kind, bearing) if kind == \"attack\" then return { { tag = \"turn\", angle = bearing } } end end }", "=test", vec![], &LoadOptions::default()
+            )
+            .unwrap();
+            let res: Commands = character
+                .on_event(&Event::Sound(SoundKind::Attack, 0.75))
+                .unwrap();
+            assert_eq!(res.value, vec![Command::Turn(0.75)]);
+        }
+
+        #[test]
+        fn round_end_calls_on_round_ended() {
+            let mut character = LuaImpl::new(
+                "return {
+                    on_round_started = function(n) return { { tag = \"turn\", angle = 1.0 } } end,
+                    on_round_ended = function(winner) return { { tag = \"turn\", angle = winner == nil and 2.0 or 3.0 } } end,
+                }",
+                "=test",
+                vec![],
+                &LoadOptions::default(),
+            )
+            .unwrap();
+            let res: Commands = character.on_event(&Event::RoundEnded(None)).unwrap();
+            assert_eq!(res.value, vec![Command::Turn(2.0)]);
+            let winner = Winner::Team("red".to_string());
+            let res: Commands = character
+                .on_event(&Event::RoundEnded(Some(winner)))
+                .unwrap();
+            assert_eq!(res.value, vec![Command::Turn(3.0)]);
+        }
+
+        #[test]
+        fn endless_loops_exceed_the_budget() {
+            let mut character = LuaImpl::new(
+                "return { on_round_started = function(n) if n == 1 then while true do end end return { { tag = \"turn\", angle = 1.0 } } end }",
+                "=test",
+                vec![],
+                &LoadOptions::default(),
+            )
+            .unwrap();
+            character.start_turn(1).unwrap();
+            let err = character.on_event(&Event::RoundStarted(1)).err().unwrap();
+            assert_eq!(err.kind, EventErrorKind::BudgetExceeded);
+            character.start_turn(1).unwrap();
+            let res: Commands = character.on_event(&Event::RoundStarted(2)).unwrap();
+            assert_eq!(res.value, vec![Command::Turn(1.0)]);
+        }
+
+        #[test]
+        fn budget_cannot_be_escaped_with_pcall() {
+            let mut character = LuaImpl::new(
+                "return { on_round_started = function(n) pcall(function() while true do end end) return {} end }",
+                "=test",
+                vec![],
+                &LoadOptions::default(),
+            )
+            .unwrap();
+            character.start_turn(1).unwrap();
+            let err = character.on_event(&Event::RoundStarted(1)).err().unwrap();
+            assert_eq!(err.kind, EventErrorKind::BudgetExceeded);
+        }
+
+        #[test]
+        fn budget_cannot_be_escaped_with_pcall_in_a_loop() {
+            let mut character = LuaImpl::new(
+                "return { on_round_started = function(n)
+                    if n == 1 then
+                        while true do pcall(function() while true do end end) end
+                    end
+                    return { { tag = \"turn\", angle = 1.0 } }
+                end }",
+                "=test",
+                vec![],
+                &LoadOptions::default(),
+            )
+            .unwrap();
+            character.start_turn(1).unwrap();
+            let err = character.on_event(&Event::RoundStarted(1)).err().unwrap();
+            assert_eq!(err.kind, EventErrorKind::BudgetExceeded);
+            // The next turn runs with the whole budget again
+            character.start_turn(2).unwrap();
+            let res: Commands = character.on_event(&Event::RoundStarted(2)).unwrap();
+            assert_eq!(res.value, vec![Command::Turn(1.0)]);
+        }
+
+        #[test]
+        fn memory_limit_is_enforced() {
+            let options = LoadOptions {
+                memory_limit: 1024 * 1024,
+                ..Default::default()
+            };
+            let mut character = LuaImpl::new(
+                "return { on_round_started = function(n) local s = string.rep(\"x\", 4 * 1024 * 1024) return {} end }",
+                "=test",
+                vec![],
+                &options,
+            )
+            .unwrap();
+            character.start_turn(1).unwrap();
+            let err = character.on_event(&Event::RoundStarted(1)).err().unwrap();
+            assert_eq!(err.kind, EventErrorKind::MemoryExceeded);
+        }
+
         #[test]
         fn call_on_tick_if_missing() {
-            let mut character = LuaImpl::new("return {}").unwrap();
+            let mut character =
+                LuaImpl::new("return {}", "=test", vec![], &LoadOptions::default()).unwrap();
             let res: Commands = character.on_event(&Event::RoundStarted(17)).unwrap();
             assert_eq!(res.value.len(), 0);
         }
     }
+
+    mod sandbox {
+        use super::*;
+
+        fn strict(seed: u64) -> LuaImpl {
+            let options = LoadOptions {
+                lua_sandbox: LuaSandbox::Strict,
+                seed,
+                ..Default::default()
+            };
+            let code = "return { on_round_started = function(n)
+                x = { io == nil, os.execute == nil, os.getenv == nil, os.time(), os.clock() }
+                y = { math.random(1000000), math.random(1000000) }
+            end }";
+            let mut character = LuaImpl::new(code, "=test", vec![], &options).unwrap();
+            character.start_turn(42).unwrap();
+            character.on_event(&Event::RoundStarted(1)).unwrap();
+            character
+        }
+
+        fn eval<T: for<'a> FromLuaMulti<'a>>(character: &LuaImpl, code: &str) -> T {
+            character.lua.load(code).eval().unwrap()
+        }
+
+        #[test]
+        fn strict_sandbox_has_no_files_and_tells_the_tick() {
+            let character = strict(1);
+            let x: (bool, bool, bool, u32, f64) =
+                eval(&character, "return x[1], x[2], x[3], x[4], x[5]");
+            assert_eq!(x, (true, true, true, 42, 42.0));
+        }
+
+        #[test]
+        fn strict_sandbox_random_depends_only_on_seed() {
+            let random = |seed| eval::<(i64, i64)>(&strict(seed), "return y[1], y[2]");
+            assert_eq!(random(7), random(7));
+            assert_ne!(random(7), random(8));
+        }
+
+        #[test]
+        fn strict_sandbox_loads_only_source_code() {
+            let character = strict(1);
+            let x: (bool, bool, i64) = eval(
+                &character,
+                "local binary = string.dump(function() return 1 end)
+                return load(binary) == nil, load(binary, \"b\", \"b\") == nil, load(\"return 2\")()",
+            );
+            assert_eq!(x, (true, true, 2));
+            assert!(eval::<bool>(
+                &character,
+                "return loadfile == nil and dofile == nil"
+            ));
+        }
+
+        #[test]
+        fn standard_sandbox_keeps_io() {
+            let character =
+                LuaImpl::new("return {}", "=test", vec![], &LoadOptions::default()).unwrap();
+            assert!(eval::<bool>(&character, "return io ~= nil"));
+        }
+    }
+
+    mod data {
+        use super::*;
+        use crate::test_utils::TempDir;
+
+        fn lua_with_data(data: Option<DataDir>) -> Lua {
+            let lua = Lua::new();
+            let mut me = lua.create_table().unwrap();
+            register_data(&mut me, &lua, data).unwrap();
+            lua.globals().set("me", me).unwrap();
+            lua
+        }
+
+        #[test]
+        fn data_can_be_written_and_read_back() {
+            let root = TempDir::new();
+            let data = DataDir::open(root.path(), &Id(uuid::Uuid::now_v7()), 10).unwrap();
+            let lua = lua_with_data(Some(data));
+            lua.load(
+                "assert(me.write_data(\"x\", \"abc\"))
+                assert(me.read_data(\"x\") == \"abc\")
+                assert(me.read_data(\"y\") == nil)
+                local ok, err = me.write_data(\"y\", \"12345678\")
+                assert(not ok and err:find(\"quota\"))
+                local ok, err = me.write_data(\"../y\", \"1\")
+                assert(not ok and err:find(\"invalid\"))",
+            )
+            .exec()
+            .unwrap();
+        }
+
+        #[test]
+        fn without_data_directory_nothing_is_kept() {
+            let lua = lua_with_data(None);
+            lua.load(
+                "assert(not me.write_data(\"x\", \"abc\"))
+                assert(me.read_data(\"x\") == nil)",
+            )
+            .exec()
+            .unwrap();
+        }
+    }
+
+    mod require {
+        use super::*;
+        use crate::test_utils::TempDir;
+
+        fn module_dir(files: &[(&str, &str)]) -> TempDir {
+            let dir = TempDir::new();
+            for (name, code) in files {
+                let path = dir.path().join(name);
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                std::fs::write(path, code).unwrap();
+            }
+            dir
+        }
+
+        fn load(code: &str, dirs: &[&Path], options: &LoadOptions) -> LuaResult<LuaImpl> {
+            let dirs = dirs.iter().map(|dir| dir.to_path_buf()).collect();
+            LuaImpl::new(code, "=test", dirs, options)
+        }
+
+        #[test]
+        fn modules_are_found_in_the_module_dirs() {
+            let dir = module_dir(&[
+                ("targeting.lua", "return { lead = 3 }"),
+                ("movement/init.lua", "return { step = 4 }"),
+            ]);
+            let lua = load(
+                "return { x = require(\"targeting\").lead + require(\"movement\").step }",
+                &[dir.path()],
+                &LoadOptions::default(),
+            )
+            .unwrap();
+            assert_eq!(lua.table().unwrap().get::<_, i64>("x").unwrap(), 7);
+        }
+
+        #[test]
+        fn modules_are_loaded_once() {
+            let dir = module_dir(&[("counter.lua", "count = (count or 0) + 1 return {}")]);
+            let lua = load(
+                "require(\"counter\") require(\"counter\") return { count = count }",
+                &[dir.path()],
+                &LoadOptions::default(),
+            )
+            .unwrap();
+            assert_eq!(lua.table().unwrap().get::<_, i64>("count").unwrap(), 1);
+        }
+
+        #[test]
+        fn shared_library_is_searched_last() {
+            let dir = module_dir(&[("util.lua", "return 1")]);
+            let library = module_dir(&[("util.lua", "return 2"), ("shared.lua", "return 3")]);
+            let options = LoadOptions {
+                lua_library: Some(library.path().to_path_buf()),
+                ..Default::default()
+            };
+            let lua = load(
+                "return { x = require(\"util\") * 10 + require(\"shared\") }",
+                &[dir.path()],
+                &options,
+            )
+            .unwrap();
+            assert_eq!(lua.table().unwrap().get::<_, i64>("x").unwrap(), 13);
+        }
+
+        #[test]
+        fn files_outside_the_module_dirs_are_refused() {
+            let outside = module_dir(&[("secret.lua", "return 1")]);
+            let dir = module_dir(&[]);
+            std::os::unix::fs::symlink(
+                outside.path().join("secret.lua"),
+                dir.path().join("link.lua"),
+            )
+            .unwrap();
+            let secret = outside.path().file_name().unwrap().to_str().unwrap();
+            for code in [
+                format!("return require(\"..{secret}.secret\")"),
+                format!("return require(\"../{secret}/secret\")"),
+                "return require(\"link\")".to_string(),
+                "return require(\"/etc/passwd\")".to_string(),
+                "return dofile(\"/etc/passwd\")".to_string(),
+            ] {
+                assert!(
+                    load(&code, &[dir.path()], &LoadOptions::default()).is_err(),
+                    "{code}"
+                );
+            }
+        }
+
+        #[test]
+        fn missing_modules_are_reported() {
+            let err = load("return require(\"nope\")", &[], &LoadOptions::default())
+                .err()
+                .unwrap();
+            assert!(err.to_string().contains("module 'nope' not found"), "{err}");
+        }
+    }
 }
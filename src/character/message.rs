@@ -0,0 +1,66 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum MessageValue {
+    Boolean(bool),
+    Integer(i64),
+    Number(f64),
+    Text(String),
+}
+
+impl MessageValue {
+    fn size(&self) -> usize {
+        match self {
+            MessageValue::Boolean(_) => 1,
+            MessageValue::Integer(_) | MessageValue::Number(_) => 8,
+            MessageValue::Text(s) => s.len(),
+        }
+    }
+}
+
+/// The payload of a broadcast: either a string or a flat table
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Message {
+    Text(String),
+    Table(BTreeMap<String, MessageValue>),
+}
+
+impl Message {
+    /// The approximate size in bytes, which is what counts against the
+    /// message limit.
+    pub fn size(&self) -> usize {
+        match self {
+            Message::Text(s) => s.len(),
+            Message::Table(entries) => entries
+                .iter()
+                .map(|(key, value)| key.len() + value.size())
+                .sum(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod message {
+        use super::*;
+
+        #[test]
+        fn size_of_text() {
+            assert_eq!(Message::Text("hello".to_string()).size(), 5);
+        }
+
+        #[test]
+        fn size_of_table() {
+            let entries = BTreeMap::from([
+                ("x".to_string(), MessageValue::Number(1.0)),
+                ("ok".to_string(), MessageValue::Boolean(true)),
+                ("target".to_string(), MessageValue::Text("Kai".to_string())),
+            ]);
+            assert_eq!(Message::Table(entries).size(), 1 + 8 + 2 + 1 + 6 + 3);
+        }
+    }
+}
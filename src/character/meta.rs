@@ -1,14 +1,24 @@
+use serde::{Deserialize, Serialize};
+
 use super::*;
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct Meta {
     pub id: Id,
     pub name: String,
     pub color: Color,
     pub version: String,
     pub entrypoint: PathBuf,
+    /// The program and its arguments for characters running as a separate
+    /// process, which makes `entrypoint` optional
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<Vec<String>>,
     // TODO: do this properly (by nesting types)
     pub instance: u8,
+    /// Set from the battle configuration, characters without a team fight
+    /// everybody else
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub team: Option<String>,
 }
 
 #[derive(Debug)]
@@ -30,6 +40,18 @@ impl Meta {
         format!("{}_{}{}", self.name, self.version, instance_counter)
     }
 
+    /// Stays the same over the whole battle and differs between instances of
+    /// the same character.
+    pub fn unique_id(&self) -> String {
+        format!("{}-{}", self.id.0, self.instance)
+    }
+
+    /// Whether both characters are in the same team. Characters are never
+    /// their own allies.
+    pub fn allied_with(&self, other: &Meta) -> bool {
+        self != other && self.team.is_some() && self.team == other.team
+    }
+
     // FIXME: add proper error handling and refactor
     fn from_toml_str(toml: &str) -> Result<Self, LoadMetaError> {
         let table = toml
@@ -44,13 +66,31 @@ impl Meta {
             .get("version")
             .map_or("1.0", |v| v.as_str().unwrap_or("1.0"))
             .to_string();
-        let entrypoint = table
-            .get("entrypoint")
-            .ok_or(LoadMetaError(format!("'entrypoint' missing")))?
-            .as_str()
-            .ok_or(LoadMetaError(format!("'entrypoint' is not a string")))?
-            .to_string()
-            .into();
+        let command = match table.get("command") {
+            Some(command) => Some(
+                command
+                    .as_array()
+                    .and_then(|args| {
+                        args.iter()
+                            .map(|arg| arg.as_str().map(str::to_string))
+                            .collect::<Option<Vec<_>>>()
+                    })
+                    .filter(|args| !args.is_empty())
+                    .ok_or(LoadMetaError(
+                        "'command' is not a non-empty list of strings".to_string(),
+                    ))?,
+            ),
+            None => None,
+        };
+        let entrypoint = match (table.get("entrypoint"), &command) {
+            (None, Some(command)) => command[0].clone(),
+            (entrypoint, _) => entrypoint
+                .ok_or(LoadMetaError("'entrypoint' missing".to_string()))?
+                .as_str()
+                .ok_or(LoadMetaError("'entrypoint' is not a string".to_string()))?
+                .to_string(),
+        }
+        .into();
         let color = table.get("color").map_or(Self::DEFAULT_COLOR, |c| {
             c.as_table()
                 .map(|color_table| Color {
@@ -65,8 +105,10 @@ impl Meta {
             id,
             version,
             entrypoint,
+            command,
             color,
             instance: 1,
+            team: None,
         })
     }
 
@@ -136,5 +178,54 @@ entrypoint = \"nya.wasm\"
             let meta = Meta::from_toml_str(toml_str).unwrap();
             assert_eq!(meta.color, Meta::DEFAULT_COLOR);
         }
+
+        #[test]
+        fn command_replaces_entrypoint() {
+            let toml_str = "
+name = \"Py\"
+id = \"00000000-0000-0000-0000-000000000000\"
+command = [\"python3\", \"bot.py\"]
+";
+            let meta = Meta::from_toml_str(toml_str).unwrap();
+            assert_eq!(
+                meta.command,
+                Some(vec!["python3".to_string(), "bot.py".to_string()])
+            );
+            assert_eq!(meta.entrypoint.to_str().unwrap(), "python3");
+            assert!(
+                Meta::from_toml_str(&toml_str.replace("[\"python3\", \"bot.py\"]", "[]")).is_err()
+            );
+        }
+
+        #[test]
+        fn only_characters_in_the_same_team_are_allied() {
+            let toml_str = "
+name = \"Nya\"
+id = \"00000000-0000-0000-0000-000000000000\"
+entrypoint = \"nya.wasm\"
+";
+            let meta = Meta::from_toml_str(toml_str).unwrap();
+            let other = Meta {
+                instance: 2,
+                ..meta.clone()
+            };
+            assert!(!meta.allied_with(&other));
+
+            let red = Meta {
+                team: Some("red".to_string()),
+                ..meta.clone()
+            };
+            let other_red = Meta {
+                team: Some("red".to_string()),
+                ..other.clone()
+            };
+            let blue = Meta {
+                team: Some("blue".to_string()),
+                ..other
+            };
+            assert!(red.allied_with(&other_red));
+            assert!(!red.allied_with(&red));
+            assert!(!red.allied_with(&blue));
+        }
     }
 }
@@ -0,0 +1,550 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::process::{Child, Command as ProcessCommand, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use super::*;
+
+/// How long a process may take to start and answer the `init` event
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// In bytes. Longer lines would have to be buffered without limit, getting
+/// around the memory limit.
+const MAX_LINE_LENGTH: usize = 1024 * 1024;
+
+enum Line {
+    Text(String),
+    TooLong,
+}
+
+/// Like `BufRead::read_line`, but only reads up to `MAX_LINE_LENGTH` bytes.
+/// The rest of a longer line is left for the next read.
+fn read_line(reader: &mut impl BufRead) -> Option<Line> {
+    let mut buf = Vec::new();
+    match reader
+        .take(MAX_LINE_LENGTH as u64 + 1)
+        .read_until(b'\n', &mut buf)
+    {
+        Ok(0) | Err(_) => None,
+        Ok(_) if buf.last() != Some(&b'\n') && buf.len() > MAX_LINE_LENGTH => Some(Line::TooLong),
+        Ok(_) => {
+            let line = String::from_utf8_lossy(&buf);
+            Some(Line::Text(line.trim_end_matches(['\n', '\r']).to_string()))
+        }
+    }
+}
+
+/// A character running as a separate program. Every event is written to its
+/// stdin as one line of JSON with a sequence number, `seq`, and the program
+/// answers each with one line `{"seq": ..., "commands": [...]}`. Commands look
+/// like the tables returned by Lua characters. Whatever the program writes to
+/// stderr is logged.
+pub struct ProcessImpl {
+    child: Child,
+    /// Lines for the writer thread, which confirms every write through
+    /// `written`
+    writer: Sender<String>,
+    written: Receiver<std::io::Result<()>>,
+    lines: Receiver<Line>,
+    seq: u64,
+    time_limit: Duration,
+    deadline: Instant,
+}
+
+impl ProcessImpl {
+    pub fn load(
+        character_dir: &Path,
+        meta: &meta::Meta,
+        options: &LoadOptions,
+    ) -> Result<Self, EventError> {
+        let command = meta.command.as_deref().unwrap_or_default();
+        let (program, args) = command.split_first().ok_or_else(|| EventError {
+            kind: EventErrorKind::Other,
+            message: "no command given".to_string(),
+        })?;
+        let mut child = ProcessCommand::new(program)
+            .args(args)
+            .current_dir(character_dir)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let mut stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        let stderr = child.stderr.take().unwrap();
+
+        // Writing and reading happen on separate threads, so that a program
+        // not reading its input or not answering can't block the battle
+        let (writer, to_write) = mpsc::channel::<String>();
+        let (write_result, written) = mpsc::channel();
+        std::thread::spawn(move || {
+            for line in to_write {
+                let res = writeln!(stdin, "{line}").and_then(|_| stdin.flush());
+                if write_result.send(res).is_err() {
+                    break;
+                }
+            }
+        });
+        let (sender, lines) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut stdout = BufReader::new(stdout);
+            while let Some(line) = read_line(&mut stdout) {
+                let too_long = matches!(line, Line::TooLong);
+                if sender.send(line).is_err() || too_long {
+                    break;
+                }
+            }
+        });
+        let name = meta.display_name();
+        std::thread::spawn(move || {
+            let mut stderr = BufReader::new(stderr);
+            while let Some(line) = read_line(&mut stderr) {
+                match line {
+                    Line::Text(line) => log_msg(&name, &line),
+                    Line::TooLong => log_msg(&name, "(line too long, skipped)"),
+                }
+            }
+        });
+
+        let mut res = Self {
+            child,
+            writer,
+            written,
+            lines,
+            seq: 0,
+            time_limit: options.process_time_limit,
+            deadline: Instant::now() + STARTUP_TIMEOUT,
+        };
+        res.call(json!({
+            "event": "init",
+            "name": meta.name,
+            "unique_id": meta.unique_id(),
+            "seed": options.seed,
+        }))?;
+        Ok(res)
+    }
+
+    /// Sends an event and waits for the commands, at most until the deadline
+    /// of the turn. Answers to earlier events that came too late are skipped.
+    fn call(&mut self, mut event: Value) -> Result<Commands, EventError> {
+        let seq = self.seq;
+        self.seq += 1;
+        event["seq"] = json!(seq);
+        self.write(&event)?;
+        loop {
+            let timeout = self.deadline.saturating_duration_since(Instant::now());
+            let line = match self.lines.recv_timeout(timeout) {
+                Ok(Line::Text(line)) => line,
+                Ok(Line::TooLong) => {
+                    return Err(EventError {
+                        kind: EventErrorKind::InvalidCommand,
+                        message: format!(
+                            "answer to {} longer than {MAX_LINE_LENGTH} bytes",
+                            event["event"]
+                        ),
+                    })
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(EventError {
+                        kind: EventErrorKind::BudgetExceeded,
+                        message: format!("no answer to {} in time", event["event"]),
+                    })
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    // Closing stdout doesn't mean that the program has ended
+                    let message = match self.child.try_wait()? {
+                        Some(status) => format!("process exited ({status})"),
+                        None => {
+                            self.child.kill()?;
+                            self.child.wait()?;
+                            "process closed its output, process killed".to_string()
+                        }
+                    };
+                    return Err(EventError {
+                        kind: EventErrorKind::Other,
+                        message,
+                    });
+                }
+            };
+            let answer: Answer = serde_json::from_str(&line).map_err(|e| EventError {
+                kind: EventErrorKind::InvalidCommand,
+                message: format!("invalid answer to {}: {e}: {line}", event["event"]),
+            })?;
+            if answer.seq == seq {
+                let commands = answer
+                    .commands
+                    .into_iter()
+                    .map(Command::try_from)
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| EventError {
+                        kind: EventErrorKind::InvalidCommand,
+                        message: format!("invalid command returned for {}: {e}", event["event"]),
+                    })?;
+                return Ok(Commands::from(commands));
+            }
+        }
+    }
+}
+
+impl ProcessImpl {
+    /// A program that doesn't read its input in time is killed, as it would
+    /// fall behind further with every event
+    fn write(&mut self, event: &Value) -> Result<(), EventError> {
+        let exited = || EventError {
+            kind: EventErrorKind::Other,
+            message: "process exited".to_string(),
+        };
+        self.writer.send(event.to_string()).map_err(|_| exited())?;
+        let timeout = self.deadline.saturating_duration_since(Instant::now());
+        match self.written.recv_timeout(timeout) {
+            Ok(res) => Ok(res?),
+            Err(RecvTimeoutError::Timeout) => {
+                self.child.kill()?;
+                Err(EventError {
+                    kind: EventErrorKind::Other,
+                    message: format!("{} not read in time, process killed", event["event"]),
+                })
+            }
+            Err(RecvTimeoutError::Disconnected) => Err(exited()),
+        }
+    }
+}
+
+impl Drop for ProcessImpl {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+impl From<std::io::Error> for EventError {
+    fn from(value: std::io::Error) -> Self {
+        Self {
+            kind: EventErrorKind::Other,
+            message: value.to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Answer {
+    seq: u64,
+    commands: Vec<CommandMessage>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum DirectionMessage {
+    Forward,
+    Backward,
+    Left,
+    Right,
+}
+
+impl From<DirectionMessage> for MovementDirection {
+    fn from(value: DirectionMessage) -> Self {
+        match value {
+            DirectionMessage::Forward => MovementDirection::Forward,
+            DirectionMessage::Backward => MovementDirection::Backward,
+            DirectionMessage::Left => MovementDirection::Left,
+            DirectionMessage::Right => MovementDirection::Right,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "tag", rename_all = "snake_case", deny_unknown_fields)]
+enum CommandMessage {
+    Move {
+        distance: f32,
+        direction: DirectionMessage,
+    },
+    Attack {
+        #[serde(default)]
+        kind: AttackKind,
+    },
+    Turn {
+        angle: f32,
+    },
+    TurnHead {
+        angle: f32,
+    },
+    TurnArms {
+        angle: f32,
+    },
+    Broadcast {
+        message: Value,
+    },
+}
+
+fn finite(key: &str, n: f32) -> Result<f32, String> {
+    if n.is_finite() {
+        Ok(n)
+    } else {
+        Err(format!("'{key}' must be a finite number, got {n}"))
+    }
+}
+
+impl TryFrom<CommandMessage> for Command {
+    type Error = String;
+
+    fn try_from(value: CommandMessage) -> Result<Self, Self::Error> {
+        match value {
+            CommandMessage::Move {
+                distance,
+                direction,
+            } => {
+                let distance = finite("distance", distance)?;
+                if distance < 0.0 {
+                    return Err(format!("'distance' must not be negative, got {distance}"));
+                }
+                Ok(Command::Move(direction.into(), distance))
+            }
+            CommandMessage::Attack { kind } => Ok(Command::Attack(kind)),
+            CommandMessage::Turn { angle } => Ok(Command::Turn(finite("angle", angle)?)),
+            CommandMessage::TurnHead { angle } => Ok(Command::TurnHead(finite("angle", angle)?)),
+            CommandMessage::TurnArms { angle } => Ok(Command::TurnArms(finite("angle", angle)?)),
+            CommandMessage::Broadcast { message } => {
+                Ok(Command::Broadcast(message_from_json(message)?))
+            }
+        }
+    }
+}
+
+fn message_from_json(value: Value) -> Result<Message, String> {
+    let message_value = |value: Value| match value {
+        Value::Bool(b) => Ok(MessageValue::Boolean(b)),
+        Value::Number(n) => Ok(match n.as_i64() {
+            Some(n) => MessageValue::Integer(n),
+            None => MessageValue::Number(n.as_f64().unwrap_or(f64::NAN)),
+        }),
+        Value::String(s) => Ok(MessageValue::Text(s)),
+        other => Err(format!("expected boolean, number or string, got {other}")),
+    };
+    match value {
+        Value::String(s) => Ok(Message::Text(s)),
+        Value::Object(entries) => Ok(Message::Table(
+            entries
+                .into_iter()
+                .map(|(key, value)| Ok((key, message_value(value)?)))
+                .collect::<Result<_, String>>()?,
+        )),
+        other => Err(format!("expected string or flat object, got {other}")),
+    }
+}
+
+fn message_to_json(message: &Message) -> Value {
+    match message {
+        Message::Text(s) => json!(s),
+        Message::Table(entries) => Value::Object(
+            entries
+                .iter()
+                .map(|(key, value)| {
+                    let value = match value {
+                        MessageValue::Boolean(b) => json!(b),
+                        MessageValue::Integer(n) => json!(n),
+                        MessageValue::Number(n) => json!(n),
+                        MessageValue::Text(s) => json!(s),
+                    };
+                    (key.clone(), value)
+                })
+                .collect(),
+        ),
+    }
+}
+
+fn wall_side_name(side: WallSide) -> &'static str {
+    match side {
+        WallSide::Top => "top",
+        WallSide::Right => "right",
+        WallSide::Bottom => "bottom",
+        WallSide::Left => "left",
+    }
+}
+
+fn sound_kind_name(kind: SoundKind) -> &'static str {
+    match kind {
+        SoundKind::Attack => "attack",
+        SoundKind::Movement => "movement",
+    }
+}
+
+/// The arguments are the same as those of the Lua event handlers
+fn event_to_json(event: &Event) -> Value {
+    match event {
+        Event::Tick(tick, state) => json!({ "event": "tick", "tick": tick, "state": state }),
+        Event::RoundStarted(round) => json!({ "event": "round_started", "round": round }),
+        Event::RoundEnded(winner) => json!({
+            "event": "round_ended",
+            "winner": winner.as_ref().map(|winner| winner.name().to_string()),
+        }),
+        Event::RoundDrawn => json!({ "event": "round_drawn" }),
+        Event::RoundWon => json!({ "event": "round_won" }),
+        Event::EnemySeen(seen) => json!({ "event": "enemy_seen", "character": seen }),
+        Event::AllySeen(seen) => json!({ "event": "ally_seen", "character": seen }),
+        Event::Message(sender, message) => json!({
+            "event": "message",
+            "sender": sender,
+            "message": message_to_json(message),
+        }),
+        Event::Death => json!({ "event": "death" }),
+        Event::EnemyDied(name) => json!({ "event": "enemy_died", "enemy": name }),
+        Event::HitBy(meta) => json!({ "event": "hit_by", "enemy": meta.name }),
+        Event::AttackHit(meta, pos) => {
+            json!({ "event": "attack_hit", "enemy": meta.name, "pos": pos })
+        }
+        Event::Collision(meta) => json!({ "event": "collision", "other": meta.name }),
+        Event::HitWall(side, bearing) => json!({
+            "event": "hit_wall",
+            "side": wall_side_name(*side),
+            "bearing": bearing,
+        }),
+        Event::Sound(kind, bearing) => json!({
+            "event": "sound",
+            "kind": sound_kind_name(*kind),
+            "bearing": bearing,
+        }),
+        Event::SkippedTurn => json!({ "event": "skipped_turn" }),
+    }
+}
+
+impl Impl for ProcessImpl {
+    fn start_turn(&mut self, _tick: u32) -> Result<(), EventError> {
+        self.deadline = Instant::now() + self.time_limit;
+        Ok(())
+    }
+
+    fn on_event(&mut self, event: &Event) -> Result<Commands, EventError> {
+        self.call(event_to_json(event))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod process_character {
+        use super::*;
+
+        /// Answers `init` with nothing and every later event with `commands`
+        fn answering(commands: &str) -> String {
+            format!(
+                "read l; echo '{{\"seq\":0,\"commands\":[]}}'; \
+                 sed -u 's/.*\"seq\":\\([0-9]*\\).*/{{\"seq\":\\1,\"commands\":[{commands}]}}/'"
+            )
+        }
+
+        fn load(script: &str) -> Result<ProcessImpl, EventError> {
+            let meta = meta::Meta {
+                id: Id(uuid::Uuid::nil()),
+                name: "Sh".to_string(),
+                color: crate::color::Color {
+                    red: 0,
+                    green: 0,
+                    blue: 0,
+                },
+                version: "1.0".to_string(),
+                entrypoint: "sh".into(),
+                command: Some(vec!["sh".to_string(), "-c".to_string(), script.to_string()]),
+                instance: 1,
+                team: None,
+            };
+            let options = LoadOptions {
+                process_time_limit: Duration::from_millis(200),
+                ..Default::default()
+            };
+            ProcessImpl::load(Path::new("."), &meta, &options)
+        }
+
+        #[test]
+        fn commands_are_read_from_stdout() {
+            let mut character = load(&answering(
+                "{\"tag\":\"turn\",\"angle\":1.5},{\"tag\":\"attack\"}",
+            ))
+            .unwrap();
+            character.start_turn(1).unwrap();
+            let commands = character.on_event(&Event::RoundStarted(1)).unwrap();
+            assert_eq!(
+                commands.value,
+                vec![Command::Turn(1.5), Command::Attack(AttackKind::Normal)]
+            );
+        }
+
+        #[test]
+        fn slow_answers_exceed_the_budget() {
+            let mut character =
+                load("read l; echo '{\"seq\":0,\"commands\":[]}'; sleep 5").unwrap();
+            character.start_turn(1).unwrap();
+            let err = character.on_event(&Event::RoundStarted(1)).err().unwrap();
+            assert_eq!(err.kind, EventErrorKind::BudgetExceeded);
+        }
+
+        #[test]
+        fn invalid_commands_are_rejected() {
+            for commands in [
+                "{\"tag\":\"move\",\"distance\":-5,\"direction\":\"forward\"}",
+                "{\"tag\":\"move\",\"distance\":5,\"direction\":\"up\"}",
+                "{\"tag\":\"jump\"}",
+                "42",
+            ] {
+                let mut character = load(&answering(commands)).unwrap();
+                character.start_turn(1).unwrap();
+                let err = character.on_event(&Event::RoundStarted(1)).err().unwrap();
+                assert_eq!(err.kind, EventErrorKind::InvalidCommand, "{commands}");
+            }
+        }
+
+        #[test]
+        fn processes_not_reading_their_input_are_killed() {
+            let mut character = load("echo '{\"seq\":0,\"commands\":[]}'; sleep 5").unwrap();
+            character.start_turn(1).unwrap();
+            // Far more than fits into the pipe's buffer
+            let message = Message::Text("x".repeat(1024 * 1024));
+            let start = Instant::now();
+            let err = character
+                .on_event(&Event::Message("Kai".to_string(), message))
+                .err()
+                .unwrap();
+            assert_eq!(err.kind, EventErrorKind::Other);
+            assert!(start.elapsed() < Duration::from_secs(2));
+            // Killed, so this doesn't wait for `sleep`
+            character.child.wait().unwrap();
+            assert!(start.elapsed() < Duration::from_secs(2));
+        }
+
+        #[test]
+        fn processes_closing_their_output_are_killed() {
+            let mut character =
+                load("read l; echo '{\"seq\":0,\"commands\":[]}'; exec >&-; sleep 5").unwrap();
+            character.start_turn(1).unwrap();
+            let start = Instant::now();
+            let err = character.on_event(&Event::RoundStarted(1)).err().unwrap();
+            assert_eq!(err.kind, EventErrorKind::Other);
+            assert!(start.elapsed() < Duration::from_secs(2));
+        }
+
+        #[test]
+        fn overlong_answers_are_rejected() {
+            let mut character = load(
+                "read l; echo '{\"seq\":0,\"commands\":[]}'; \
+                 head -c 3000000 /dev/zero | tr '\\0' x; sleep 5",
+            )
+            .unwrap();
+            character.start_turn(1).unwrap();
+            let err = character.on_event(&Event::RoundStarted(1)).err().unwrap();
+            assert_eq!(err.kind, EventErrorKind::InvalidCommand);
+        }
+
+        #[test]
+        fn exited_processes_are_reported() {
+            let mut character = load("read l; echo '{\"seq\":0,\"commands\":[]}'").unwrap();
+            character.start_turn(1).unwrap();
+            let err = character.on_event(&Event::RoundStarted(1)).err().unwrap();
+            assert_eq!(err.kind, EventErrorKind::Other);
+        }
+    }
+}
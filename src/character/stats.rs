@@ -0,0 +1,81 @@
+use crate::settings::ScoreWeights;
+
+/// What a character achieved over the whole battle
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+    pub rounds_won: u32,
+    /// Damage dealt to enemies, i.e. not counting hits on allies
+    pub damage_dealt: f32,
+    pub damage_taken: f32,
+    pub kills: u32,
+    /// One point for every enemy that died while this character was alive
+    pub survival_points: u32,
+    pub shots_fired: u32,
+    /// Like the damage, only hits on enemies count
+    pub shots_hit: u32,
+    pub ticks_survived: u32,
+    /// How often the character's code failed with an error
+    pub crashes: u32,
+}
+
+impl Stats {
+    /// The share of shots fired that hit an enemy, 0 without any shots
+    pub fn accuracy(&self) -> f32 {
+        if self.shots_fired == 0 {
+            return 0.0;
+        }
+        self.shots_hit as f32 / self.shots_fired as f32
+    }
+
+    pub fn score(&self, weights: &ScoreWeights) -> f32 {
+        self.rounds_won as f32 * weights.round_won
+            + self.damage_dealt * weights.damage_dealt
+            + self.damage_taken * weights.damage_taken
+            + self.kills as f32 * weights.kill
+            + self.survival_points as f32 * weights.survival
+            + self.ticks_survived as f32 * weights.tick_survived
+            + self.accuracy() * weights.accuracy
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod stats {
+        use super::*;
+
+        #[test]
+        fn score_is_weighted_sum() {
+            let stats = Stats {
+                rounds_won: 1,
+                damage_dealt: 45.0,
+                damage_taken: 30.0,
+                kills: 2,
+                survival_points: 3,
+                shots_fired: 8,
+                shots_hit: 6,
+                ticks_survived: 1000,
+                crashes: 1,
+            };
+            let weights = ScoreWeights {
+                round_won: 10.0,
+                damage_dealt: 1.0,
+                damage_taken: -0.5,
+                kill: 20.0,
+                survival: 50.0,
+                tick_survived: 0.5,
+                accuracy: 100.0,
+            };
+            assert_eq!(
+                stats.score(&weights),
+                10.0 + 45.0 - 15.0 + 40.0 + 150.0 + 500.0 + 75.0
+            );
+        }
+
+        #[test]
+        fn accuracy_without_shots_is_zero() {
+            assert_eq!(Stats::default().accuracy(), 0.0);
+        }
+    }
+}
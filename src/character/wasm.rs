@@ -1,8 +1,23 @@
-use std::path::Path;
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
-use exports::luarena::character::handlers::{self, Command, Movement, MovementDirection};
+use clap::ValueEnum;
 
-use super::meta;
+use exports::luarena::character::handlers::{
+    self, AttackKind, Command, Movement, MovementDirection, SoundKind, WallSide,
+};
+
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::data::DataDir;
+use super::{meta, EventErrorKind, LoadOptions};
 use crate::math_utils;
 
 wasmtime::component::bindgen!("character");
@@ -11,32 +26,309 @@ struct MyState {
     ctx: wasmtime_wasi::WasiCtx,
     table: wasmtime_wasi::ResourceTable,
     display_name: String,
+    limiter: MemoryLimiter,
+    /// Whether the data directory, if any, was preopened for writing
+    data_writable: bool,
+}
+
+/// Enforces the memory limit through `StoreLimits`, remembering whether it
+/// was hit
+struct MemoryLimiter {
+    limits: wasmtime::StoreLimits,
+    memory_limit: usize,
+    exceeded: bool,
+}
+
+impl wasmtime::ResourceLimiter for MemoryLimiter {
+    fn memory_growing(
+        &mut self,
+        current: usize,
+        desired: usize,
+        maximum: Option<usize>,
+    ) -> wasmtime::Result<bool> {
+        if desired > self.memory_limit {
+            self.exceeded = true;
+        }
+        self.limits.memory_growing(current, desired, maximum)
+    }
+
+    fn memory_grow_failed(&mut self, error: wasmtime::Error) -> wasmtime::Result<()> {
+        self.limits.memory_grow_failed(error)
+    }
+
+    fn table_growing(
+        &mut self,
+        current: usize,
+        desired: usize,
+        maximum: Option<usize>,
+    ) -> wasmtime::Result<bool> {
+        self.limits.table_growing(current, desired, maximum)
+    }
+}
+
+/// Lets WASI clocks tell the game time instead of the host's time. Like in
+/// the strict Lua sandbox, every tick is one second.
+#[derive(Clone)]
+struct GameClock {
+    tick: Arc<AtomicU32>,
+}
+
+impl GameClock {
+    fn elapsed(&self) -> Duration {
+        Duration::from_secs(self.tick.load(Ordering::Relaxed) as u64)
+    }
+}
+
+impl wasmtime_wasi::HostWallClock for GameClock {
+    fn resolution(&self) -> Duration {
+        Duration::from_secs(1)
+    }
+
+    fn now(&self) -> Duration {
+        self.elapsed()
+    }
+}
+
+impl wasmtime_wasi::HostMonotonicClock for GameClock {
+    fn resolution(&self) -> u64 {
+        Duration::from_secs(1).as_nanos() as u64
+    }
+
+    fn now(&self) -> u64 {
+        self.elapsed().as_nanos() as u64
+    }
+}
+
+/// Access to the host that wasm characters only get if the battle grants it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WasiGrant {
+    /// The character's own directory, read-only as `/character`
+    Filesystem,
+    /// TCP, UDP and name lookups
+    Network,
+    /// The environment variables of the host
+    Env,
+}
+
+/// Everything besides the component that is needed to instantiate it anew
+struct Environment {
+    character_dir: PathBuf,
+    display_name: String,
+    options: LoadOptions,
+    data: Option<DataDir>,
+    clock: GameClock,
+}
+
+impl Environment {
+    /// Builds a WASI context without access to the host except for what is
+    /// granted. Clocks and random numbers are deterministic. The data
+    /// directory is preopened as `/data`, read-only once the character has
+    /// exceeded its quota, which is returned as well. Until then writes are
+    /// not limited, so the quota can be exceeded within a round.
+    fn wasi_ctx(&self) -> wasmtime::Result<(wasmtime_wasi::WasiCtx, bool)> {
+        let mut builder = wasmtime_wasi::WasiCtxBuilder::new();
+        let seed = self.options.seed;
+        builder
+            .wall_clock(self.clock.clone())
+            .monotonic_clock(self.clock.clone())
+            .secure_random(StdRng::seed_from_u64(seed))
+            .insecure_random(StdRng::seed_from_u64(seed.wrapping_add(1)))
+            .insecure_random_seed(seed as u128)
+            .allow_tcp(false)
+            .allow_udp(false);
+        for grant in self.options.wasi_grants.iter() {
+            match grant {
+                WasiGrant::Filesystem => {
+                    builder.preopened_dir(
+                        &self.character_dir,
+                        "/character",
+                        wasmtime_wasi::DirPerms::READ,
+                        wasmtime_wasi::FilePerms::READ,
+                    )?;
+                }
+                WasiGrant::Network => {
+                    builder
+                        .inherit_network()
+                        .allow_ip_name_lookup(true)
+                        .allow_tcp(true)
+                        .allow_udp(true);
+                }
+                WasiGrant::Env => {
+                    builder.inherit_env();
+                }
+            }
+        }
+        let mut data_writable = false;
+        if let Some(data) = &self.data {
+            data_writable = !data.exceeded()?;
+            let (dir_perms, file_perms) = if data_writable {
+                (
+                    wasmtime_wasi::DirPerms::all(),
+                    wasmtime_wasi::FilePerms::all(),
+                )
+            } else {
+                (
+                    wasmtime_wasi::DirPerms::READ,
+                    wasmtime_wasi::FilePerms::READ,
+                )
+            };
+            builder.preopened_dir(&data.path, "/data", dir_perms, file_perms)?;
+        }
+        Ok((builder.build(), data_writable))
+    }
+}
+
+/// Compiles components with one engine shared by all wasm characters. Every
+/// component is compiled only once, and the compiled code is kept on disk for
+/// later battles, keyed by the hash of the wasm file.
+pub struct ComponentCache {
+    engine: wasmtime::Engine,
+    components: HashMap<String, wasmtime::component::Component>,
+    dir: PathBuf,
+}
+
+impl ComponentCache {
+    pub fn new(dir: &Path) -> wasmtime::Result<Self> {
+        let mut config = wasmtime::Config::new();
+        config.consume_fuel(true);
+        Ok(Self {
+            engine: wasmtime::Engine::new(&config)?,
+            components: HashMap::new(),
+            dir: dir.to_path_buf(),
+        })
+    }
+
+    /// The file name of the compiled code, which also depends on the engine,
+    /// as code compiled by another version or configuration can't be used
+    fn key(&self, wasm: &[u8]) -> String {
+        let mut hasher = DefaultHasher::new();
+        self.engine
+            .precompile_compatibility_hash()
+            .hash(&mut hasher);
+        format!("{:x}-{:016x}", Sha256::digest(wasm), hasher.finish())
+    }
+
+    pub fn get(&mut self, file: &Path) -> wasmtime::Result<wasmtime::component::Component> {
+        let wasm = std::fs::read(file)?;
+        let key = self.key(&wasm);
+        if let Some(component) = self.components.get(&key) {
+            return Ok(component.clone());
+        }
+        let path = self.dir.join(format!("{key}.cwasm"));
+        // SAFETY: the cache only contains code compiled by this engine, which
+        // the key ensures. Whoever can write to the cache directory could run
+        // arbitrary code though.
+        let cached = std::fs::read(&path).ok().and_then(|bytes| unsafe {
+            wasmtime::component::Component::deserialize(&self.engine, bytes).ok()
+        });
+        let component = match cached {
+            Some(component) => component,
+            None => {
+                let component = wasmtime::component::Component::new(&self.engine, &wasm)?;
+                self.store(&path, &component.serialize()?)?;
+                component
+            }
+        };
+        self.components.insert(key, component.clone());
+        Ok(component)
+    }
+
+    /// Writes to a temporary file first, so that other battles never read
+    /// half of a file
+    fn store(&self, path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        let tmp = path.with_extension(format!("tmp-{}", std::process::id()));
+        std::fs::write(&tmp, bytes)?;
+        std::fs::rename(tmp, path)
+    }
 }
 
 pub struct WasmImpl {
     bindings: Character,
     store: wasmtime::Store<MyState>,
+    component: wasmtime::component::Component,
+    linker: wasmtime::component::Linker<MyState>,
+    env: Environment,
+    /// After a trap, e.g. from running out of fuel, the instance cannot be
+    /// entered anymore. It is replaced by a fresh one at the start of the
+    /// next turn, so besides skipping the turn, a trap resets all of the
+    /// character's state. Keeping it would take suspending the guest, i.e.
+    /// calling it asynchronously with fuel yielding.
+    needs_restart: bool,
+    /// Set at the end of a round, when the data directory is checked against
+    /// the quota. Doing that every turn would mean walking the whole
+    /// directory every tick.
+    check_data_quota: bool,
 }
 
 impl WasmImpl {
-    pub fn load(character_dir: &Path, meta: &meta::Meta) -> Result<Self, AddWasmCharacterError> {
-        let engine = wasmtime::Engine::default();
-        let file = character_dir.join(&meta.entrypoint);
-        let component = wasmtime::component::Component::from_file(&engine, file)?;
+    pub fn load(
+        character_dir: &Path,
+        meta: &meta::Meta,
+        options: &LoadOptions,
+        components: &mut ComponentCache,
+    ) -> Result<Self, AddWasmCharacterError> {
+        let component = components.get(&character_dir.join(&meta.entrypoint))?;
+        let engine = components.engine.clone();
         let mut linker = wasmtime::component::Linker::new(&engine);
         wasmtime_wasi::add_to_linker_sync(&mut linker)?;
         Character::add_to_linker(&mut linker, |state: &mut MyState| state)?;
-        let mut builder = wasmtime_wasi::WasiCtxBuilder::new();
+        let data = match &options.data_dir {
+            Some(root) => Some(DataDir::open(root, &meta.id, options.data_quota)?),
+            None => None,
+        };
+        let env = Environment {
+            character_dir: character_dir.to_path_buf(),
+            display_name: meta.display_name(),
+            options: options.clone(),
+            data,
+            clock: GameClock {
+                tick: Arc::new(AtomicU32::new(0)),
+            },
+        };
+        let (bindings, store) = Self::instantiate(&engine, &component, &linker, &env)?;
+        Ok(Self {
+            bindings,
+            store,
+            component,
+            linker,
+            env,
+            needs_restart: false,
+            check_data_quota: false,
+        })
+    }
+
+    fn instantiate(
+        engine: &wasmtime::Engine,
+        component: &wasmtime::component::Component,
+        linker: &wasmtime::component::Linker<MyState>,
+        env: &Environment,
+    ) -> wasmtime::Result<(Character, wasmtime::Store<MyState>)> {
+        let (ctx, data_writable) = env.wasi_ctx()?;
+        let memory_limit = env.options.memory_limit;
+        let limits = wasmtime::StoreLimitsBuilder::new()
+            .memory_size(memory_limit)
+            .trap_on_grow_failure(true)
+            .build();
         let mut store = wasmtime::Store::new(
-            &engine,
+            engine,
             MyState {
-                ctx: builder.build(),
+                ctx,
                 table: wasmtime_wasi::ResourceTable::new(),
-                display_name: meta.display_name(),
+                display_name: env.display_name.clone(),
+                limiter: MemoryLimiter {
+                    limits,
+                    memory_limit,
+                    exceeded: false,
+                },
+                data_writable,
             },
         );
-        let bindings = Character::instantiate::<MyState>(&mut store, &component, &linker)?;
-        Ok(Self { bindings, store })
+        store.limiter(|state| &mut state.limiter);
+        store.set_fuel(env.options.fuel_limit)?;
+        let bindings = Character::instantiate::<MyState>(&mut store, component, linker)?;
+        Ok((bindings, store))
     }
 }
 
@@ -61,6 +353,90 @@ impl From<&MovementDirection> for super::MovementDirection {
     }
 }
 
+impl From<&AttackKind> for super::AttackKind {
+    fn from(value: &AttackKind) -> Self {
+        match value {
+            AttackKind::Normal => super::AttackKind::Normal,
+            AttackKind::Fast => super::AttackKind::Fast,
+            AttackKind::Heavy => super::AttackKind::Heavy,
+            AttackKind::Melee => super::AttackKind::Melee,
+        }
+    }
+}
+
+impl From<&super::WallSide> for WallSide {
+    fn from(value: &super::WallSide) -> Self {
+        match value {
+            super::WallSide::Top => WallSide::Top,
+            super::WallSide::Right => WallSide::Right,
+            super::WallSide::Bottom => WallSide::Bottom,
+            super::WallSide::Left => WallSide::Left,
+        }
+    }
+}
+
+impl From<&super::SoundKind> for SoundKind {
+    fn from(value: &super::SoundKind) -> Self {
+        match value {
+            super::SoundKind::Attack => SoundKind::Attack,
+            super::SoundKind::Movement => SoundKind::Movement,
+        }
+    }
+}
+
+impl From<&handlers::MessageValue> for super::MessageValue {
+    fn from(value: &handlers::MessageValue) -> Self {
+        match value {
+            handlers::MessageValue::Boolean(b) => Self::Boolean(*b),
+            handlers::MessageValue::Integer(n) => Self::Integer(*n),
+            handlers::MessageValue::Number(n) => Self::Number(*n),
+            handlers::MessageValue::Text(s) => Self::Text(s.clone()),
+        }
+    }
+}
+
+impl From<&super::MessageValue> for handlers::MessageValue {
+    fn from(value: &super::MessageValue) -> Self {
+        match value {
+            super::MessageValue::Boolean(b) => Self::Boolean(*b),
+            super::MessageValue::Integer(n) => Self::Integer(*n),
+            super::MessageValue::Number(n) => Self::Number(*n),
+            super::MessageValue::Text(s) => Self::Text(s.clone()),
+        }
+    }
+}
+
+impl From<&handlers::Message> for super::Message {
+    fn from(value: &handlers::Message) -> Self {
+        match value {
+            handlers::Message::Text(s) => Self::Text(s.clone()),
+            handlers::Message::Table(entries) => Self::Table(
+                entries
+                    .iter()
+                    .map(|entry| (entry.key.clone(), (&entry.value).into()))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl From<&super::Message> for handlers::Message {
+    fn from(value: &super::Message) -> Self {
+        match value {
+            super::Message::Text(s) => Self::Text(s.clone()),
+            super::Message::Table(entries) => Self::Table(
+                entries
+                    .iter()
+                    .map(|(key, value)| handlers::MessageEntry {
+                        key: key.clone(),
+                        value: value.into(),
+                    })
+                    .collect(),
+            ),
+        }
+    }
+}
+
 impl From<&Command> for super::Command {
     fn from(value: &Command) -> Self {
         match value {
@@ -68,18 +444,26 @@ impl From<&Command> for super::Command {
                 direction,
                 distance,
             }) => Self::Move(direction.into(), *distance),
-            Command::Attack => Self::Attack,
+            Command::Attack(kind) => Self::Attack(kind.into()),
             Command::Turn(angle) => Self::Turn(*angle),
             Command::TurnHead(angle) => Self::TurnHead(*angle),
             Command::TurnArms(angle) => Self::TurnArms(*angle),
+            Command::Broadcast(message) => Self::Broadcast(message.into()),
         }
     }
 }
 
 impl From<wasmtime::Error> for super::EventError {
     fn from(value: wasmtime::Error) -> Self {
+        let kind = if value.downcast_ref::<wasmtime::Trap>() == Some(&wasmtime::Trap::OutOfFuel) {
+            EventErrorKind::BudgetExceeded
+        } else {
+            EventErrorKind::Other
+        };
+        // The debug representation includes the wasm backtrace
         Self {
-            message: value.to_string(),
+            kind,
+            message: format!("{value:?}"),
         }
     }
 }
@@ -96,6 +480,14 @@ impl From<wasmtime::Error> for AddWasmCharacterError {
     }
 }
 
+impl From<std::io::Error> for AddWasmCharacterError {
+    fn from(value: std::io::Error) -> Self {
+        Self {
+            message: value.to_string(),
+        }
+    }
+}
+
 impl CharacterImports for MyState {
     fn log(&mut self, msg: String) {
         super::log_msg(&self.display_name, &msg);
@@ -136,8 +528,82 @@ impl From<&super::CurrentCharacterState> for handlers::CharacterState {
     }
 }
 
+impl From<&super::SeenCharacter> for handlers::SeenCharacter {
+    fn from(value: &super::SeenCharacter) -> Self {
+        handlers::SeenCharacter {
+            id: value.id.clone(),
+            name: value.name.clone(),
+            pos: (&value.pos).into(),
+            heading: value.heading,
+            velocity: (&value.velocity).into(),
+            distance: value.distance,
+            bearing: value.bearing,
+            hp: value.hp,
+        }
+    }
+}
+
+impl WasmImpl {
+    fn restart(&mut self) -> wasmtime::Result<()> {
+        let engine = self.store.engine().clone();
+        let (bindings, store) =
+            Self::instantiate(&engine, &self.component, &self.linker, &self.env)?;
+        self.bindings = bindings;
+        self.store = store;
+        self.needs_restart = false;
+        Ok(())
+    }
+}
+
 impl super::Impl for WasmImpl {
+    fn start_turn(&mut self, tick: u32) -> Result<(), super::EventError> {
+        self.env.clock.tick.store(tick, Ordering::Relaxed);
+        if self.needs_restart {
+            super::log_msg(
+                &self.env.display_name,
+                "restarting after a trap, all state is lost",
+            );
+            self.restart()?;
+        }
+        // Writes through WASI can't be checked beforehand, so a character
+        // exceeding its quota loses write access instead. This makes it a soft
+        // quota: within a round, a character can write as much as it likes
+        let data_exceeded = match &self.env.data {
+            Some(data) if self.check_data_quota => data.exceeded().unwrap_or(true),
+            _ => false,
+        };
+        self.check_data_quota = false;
+        if self.store.data().data_writable && data_exceeded {
+            super::log_msg(
+                &self.env.display_name,
+                "data quota exceeded, restarting with a read-only data directory",
+            );
+            self.restart()?;
+        }
+        self.store.set_fuel(self.env.options.fuel_limit)?;
+        Ok(())
+    }
+
     fn on_event(&mut self, event: &super::Event) -> Result<super::Commands, super::EventError> {
+        if matches!(event, super::Event::RoundEnded(_)) {
+            self.check_data_quota = true;
+        }
+        let res = self.handle_event(event);
+        if self.store.data().limiter.exceeded {
+            return Err(super::EventError {
+                kind: EventErrorKind::MemoryExceeded,
+                message: "memory limit exceeded".to_string(),
+            });
+        }
+        if res.is_err() {
+            self.needs_restart = true;
+        }
+        res
+    }
+}
+
+impl WasmImpl {
+    fn handle_event(&mut self, event: &super::Event) -> Result<super::Commands, super::EventError> {
         match event {
             super::Event::Tick(tick, state) => {
                 let commands = self.bindings.luarena_character_handlers().call_on_tick(
@@ -154,11 +620,28 @@ impl super::Impl for WasmImpl {
                     .call_on_round_started(&mut self.store, *round)?;
                 Ok(super::Commands::from(commands))
             }
-            super::Event::EnemySeen(enemy, p) => {
+            super::Event::EnemySeen(enemy) => {
                 let commands = self
                     .bindings
                     .luarena_character_handlers()
-                    .call_on_enemy_seen(&mut self.store, enemy, p.into())?;
+                    .call_on_enemy_seen(
+                        &mut self.store,
+                        &enemy.name,
+                        (&enemy.pos).into(),
+                        &enemy.into(),
+                    )?;
+                Ok(super::Commands::from(commands))
+            }
+            super::Event::AllySeen(ally) => {
+                let commands = self
+                    .bindings
+                    .luarena_character_handlers()
+                    .call_on_ally_seen(
+                        &mut self.store,
+                        &ally.name,
+                        (&ally.pos).into(),
+                        &ally.into(),
+                    )?;
                 Ok(super::Commands::from(commands))
             }
             super::Event::Death => {
@@ -182,6 +665,36 @@ impl super::Impl for WasmImpl {
                     .call_on_attack_hit(&mut self.store, &enemy.name.to_string(), p.into())?;
                 Ok(super::Commands::from(commands))
             }
+            super::Event::Message(sender, message) => {
+                let commands = self.bindings.luarena_character_handlers().call_on_message(
+                    &mut self.store,
+                    sender,
+                    &message.into(),
+                )?;
+                Ok(super::Commands::from(commands))
+            }
+            super::Event::Collision(other) => {
+                let commands = self
+                    .bindings
+                    .luarena_character_handlers()
+                    .call_on_collision(&mut self.store, &other.name)?;
+                Ok(super::Commands::from(commands))
+            }
+            super::Event::HitWall(side, bearing) => {
+                let commands = self
+                    .bindings
+                    .luarena_character_handlers()
+                    .call_on_hit_wall(&mut self.store, side.into(), *bearing)?;
+                Ok(super::Commands::from(commands))
+            }
+            super::Event::Sound(kind, bearing) => {
+                let commands = self.bindings.luarena_character_handlers().call_on_sound(
+                    &mut self.store,
+                    kind.into(),
+                    *bearing,
+                )?;
+                Ok(super::Commands::from(commands))
+            }
             super::Event::EnemyDied(enemy_id) => {
                 let commands = self
                     .bindings
@@ -194,7 +707,7 @@ impl super::Impl for WasmImpl {
                     .luarena_character_handlers()
                     .call_on_round_ended(
                         &mut self.store,
-                        opt_winner.as_ref().map(|m| m.name.as_str()),
+                        opt_winner.as_ref().map(|winner| winner.name()),
                     )?;
                 Ok(super::Commands::none())
             }
@@ -210,6 +723,42 @@ impl super::Impl for WasmImpl {
                     .call_on_round_drawn(&mut self.store)?;
                 Ok(super::Commands::none())
             }
+            super::Event::SkippedTurn => {
+                let commands = self
+                    .bindings
+                    .luarena_character_handlers()
+                    .call_on_skipped_turn(&mut self.store)?;
+                Ok(super::Commands::from(commands))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod component_cache {
+        use super::*;
+        use crate::test_utils::TempDir;
+
+        #[test]
+        fn compiled_components_are_kept_on_disk() {
+            let dir = TempDir::new();
+            let file = dir.path().join("character.wasm");
+            std::fs::write(&file, "(component)").unwrap();
+            let cache_dir = dir.path().join("cache");
+
+            let mut components = ComponentCache::new(&cache_dir).unwrap();
+            components.get(&file).unwrap();
+            components.get(&file).unwrap();
+            assert_eq!(components.components.len(), 1);
+            assert_eq!(std::fs::read_dir(&cache_dir).unwrap().count(), 1);
+
+            // A corrupt cache entry is compiled again
+            let entry = std::fs::read_dir(&cache_dir).unwrap().next().unwrap();
+            std::fs::write(entry.unwrap().path(), "garbage").unwrap();
+            ComponentCache::new(&cache_dir).unwrap().get(&file).unwrap();
         }
     }
 }
@@ -1,13 +1,295 @@
+use core::fmt;
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
 
 use clap::*;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Args)]
+use crate::character::lua::LuaSandbox;
+use crate::character::wasm::WasiGrant;
+use crate::character::LoadOptions;
+use crate::map::Map;
+use crate::obstacle::Obstacle;
+use crate::settings::Rules;
+
+/// What happens when a round reaches its maximum number of ticks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TimeoutRule {
+    /// The round ends in a draw
+    Draw,
+    /// The character with the most remaining HP wins
+    Hp,
+    /// The arena starts shrinking, damaging everyone outside of it
+    SuddenDeath,
+}
+
+/// A character directory, optionally followed by `@team`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct CharacterEntry {
+    pub dir: PathBuf,
+    pub team: Option<String>,
+}
+
+impl FromStr for CharacterEntry {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.rsplit_once('@') {
+            Some((_, "")) => Err(format!("missing team name in '{s}'")),
+            Some((dir, team)) => Ok(Self {
+                dir: dir.into(),
+                team: Some(team.to_string()),
+            }),
+            None => Ok(Self {
+                dir: s.into(),
+                team: None,
+            }),
+        }
+    }
+}
+
+impl TryFrom<String> for CharacterEntry {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<CharacterEntry> for String {
+    fn from(entry: CharacterEntry) -> Self {
+        entry.to_string()
+    }
+}
+
+impl fmt::Display for CharacterEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.dir.display())?;
+        if let Some(team) = &self.team {
+            write!(f, "@{team}")?;
+        }
+        Ok(())
+    }
+}
+
+/// What happens to a character whose code fails with an error
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CrashPolicy {
+    /// The character stays in the round, but does nothing that turn
+    Skip,
+    /// The character is removed from the current round
+    Remove,
+}
+
+fn default_crash_policy() -> CrashPolicy {
+    CrashPolicy::Skip
+}
+
+/// Who receives the messages a character broadcasts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MessageScope {
+    /// Every other character
+    All,
+    /// Only characters of the same team
+    Team,
+    /// Only other instances of the same character
+    SameId,
+}
+
+fn default_message_scope() -> MessageScope {
+    MessageScope::All
+}
+
+fn default_message_limit() -> usize {
+    256
+}
+
+fn default_max_ticks() -> u32 {
+    10_000
+}
+
+fn default_instruction_limit() -> u64 {
+    LoadOptions::default().instruction_limit
+}
+
+fn default_fuel_limit() -> u64 {
+    LoadOptions::default().fuel_limit
+}
+
+fn default_memory_limit() -> usize {
+    LoadOptions::default().memory_limit / (1024 * 1024)
+}
+
+fn default_process_time_limit() -> u64 {
+    LoadOptions::default().process_time_limit.as_millis() as u64
+}
+
+fn default_wasm_cache() -> PathBuf {
+    PathBuf::from(".wasm-cache")
+}
+
+fn default_lua_sandbox() -> LuaSandbox {
+    LuaSandbox::Standard
+}
+
+fn default_data_quota() -> u64 {
+    LoadOptions::default().data_quota / 1024
+}
+
+fn default_max_skipped_turns() -> u32 {
+    10
+}
+
+fn default_timeout_rule() -> TimeoutRule {
+    TimeoutRule::SuddenDeath
+}
+
+#[derive(Debug, Clone, Args, Serialize, Deserialize)]
 pub struct BattleConfiguration {
+    /// A character directory; append `@team` to put characters into teams
     #[arg(short = 'c', long = "character")]
-    pub characters: Vec<PathBuf>,
+    pub characters: Vec<CharacterEntry>,
     #[arg(short = 'r', long = "rounds", default_value_t = 10)]
     pub rounds: u16,
+    #[arg(long = "record")]
+    #[serde(skip)]
+    pub record: Option<PathBuf>,
+    #[arg(short = 's', long = "seed")]
+    #[serde(default)]
+    pub seed: Option<u64>,
+    #[arg(long = "max-ticks", default_value_t = default_max_ticks())]
+    #[serde(default = "default_max_ticks")]
+    pub max_ticks: u32,
+    #[arg(long = "timeout-rule", value_enum, default_value_t = default_timeout_rule())]
+    #[serde(default = "default_timeout_rule")]
+    pub timeout_rule: TimeoutRule,
+    /// Damage each character takes when running into another one
+    #[arg(long = "ram-damage", default_value_t = 0.0)]
+    #[serde(default)]
+    pub ram_damage: f32,
+    /// Damage a character takes when trying to move into a wall
+    #[arg(long = "wall-damage", default_value_t = 0.0)]
+    #[serde(default)]
+    pub wall_damage: f32,
+    /// Whether attacks and collisions hurt characters of the same team
+    #[arg(long = "friendly-fire", default_value_t = false)]
+    #[serde(default)]
+    pub friendly_fire: bool,
+    #[arg(long = "message-scope", value_enum, default_value_t = default_message_scope())]
+    #[serde(default = "default_message_scope")]
+    pub message_scope: MessageScope,
+    /// The number of bytes a character may broadcast per tick
+    #[arg(long = "message-limit", default_value_t = default_message_limit())]
+    #[serde(default = "default_message_limit")]
+    pub message_limit: usize,
+    /// A `rules.toml` overriding the default rules
+    #[arg(long = "rules", value_parser = Rules::parse_file_arg)]
+    #[serde(default)]
+    pub rules: Option<Rules>,
+    /// An obstacle in the arena: `circle:x,y,radius` or `rect:x,y,width,height`
+    #[arg(long = "obstacle")]
+    #[serde(default)]
+    pub obstacles: Vec<Obstacle>,
+    /// A map file defining the arena's size, spawn points, blocked regions and
+    /// hazards
+    #[arg(long = "map", value_parser = Map::parse_file_arg)]
+    #[serde(default)]
+    pub map: Option<Map>,
+    /// The number of instructions a Lua character may execute per tick
+    #[arg(long = "instruction-limit", default_value_t = default_instruction_limit())]
+    #[serde(default = "default_instruction_limit")]
+    pub instruction_limit: u64,
+    /// The amount of fuel a wasm character may consume per tick. Running out
+    /// traps, which skips the turn and resets the character to a fresh
+    /// instance, losing all of its state
+    #[arg(long = "fuel-limit", default_value_t = default_fuel_limit())]
+    #[serde(default = "default_fuel_limit")]
+    pub fuel_limit: u64,
+    /// The memory a character may use, in MiB. Characters exceeding it are
+    /// disqualified.
+    #[arg(long = "memory-limit", default_value_t = default_memory_limit())]
+    #[serde(default = "default_memory_limit")]
+    pub memory_limit: usize,
+    /// How many turns a character may skip for exceeding its budget before
+    /// it is disqualified
+    #[arg(long = "max-skipped-turns", default_value_t = default_max_skipped_turns())]
+    #[serde(default = "default_max_skipped_turns")]
+    pub max_skipped_turns: u32,
+    #[arg(long = "crash-policy", value_enum, default_value_t = default_crash_policy())]
+    #[serde(default = "default_crash_policy")]
+    pub crash_policy: CrashPolicy,
+    /// A directory of Lua modules every Lua character may `require`, besides
+    /// the modules in its own directory
+    #[arg(long = "lua-library")]
+    #[serde(default)]
+    pub lua_library: Option<PathBuf>,
+    #[arg(long = "lua-sandbox", value_enum, default_value_t = default_lua_sandbox())]
+    #[serde(default = "default_lua_sandbox")]
+    pub lua_sandbox: LuaSandbox,
+    /// A directory in which every character gets its own directory to keep
+    /// data between battles
+    #[arg(long = "data-dir")]
+    #[serde(default)]
+    pub data_dir: Option<PathBuf>,
+    /// The size of a character's data directory, in KiB. Lua characters can't
+    /// write beyond it; wasm characters write through WASI, which is only
+    /// checked between rounds, so they can exceed it within a round before
+    /// their directory becomes read-only
+    #[arg(long = "data-quota", default_value_t = default_data_quota())]
+    #[serde(default = "default_data_quota")]
+    pub data_quota: u64,
+    /// Access to the host a wasm character gets, which is none by default
+    #[arg(long = "wasi-grant", value_enum)]
+    #[serde(default)]
+    pub wasi_grants: Vec<WasiGrant>,
+    /// Where compiled wasm characters are kept for later battles
+    #[arg(long = "wasm-cache", default_value_os_t = default_wasm_cache())]
+    #[serde(default = "default_wasm_cache")]
+    pub wasm_cache: PathBuf,
+    /// The milliseconds a character running as a separate process may take
+    /// per tick
+    #[arg(long = "process-time-limit", default_value_t = default_process_time_limit())]
+    #[serde(default = "default_process_time_limit")]
+    pub process_time_limit: u64,
+}
+
+impl BattleConfiguration {
+    /// The rules of the battle, with the arena size taken from the map.
+    pub fn effective_rules(&self) -> Rules {
+        let mut rules = self.rules.clone().unwrap_or_default();
+        if let Some(map) = &self.map {
+            rules.width = map.width.unwrap_or(rules.width);
+            rules.height = map.height.unwrap_or(rules.height);
+        }
+        rules
+    }
+
+    pub fn load_options(&self) -> LoadOptions {
+        LoadOptions {
+            instruction_limit: self.instruction_limit,
+            fuel_limit: self.fuel_limit,
+            memory_limit: self.memory_limit * 1024 * 1024,
+            lua_library: self.lua_library.clone(),
+            data_dir: self.data_dir.clone(),
+            data_quota: self.data_quota * 1024,
+            lua_sandbox: self.lua_sandbox,
+            wasi_grants: self.wasi_grants.clone(),
+            process_time_limit: Duration::from_millis(self.process_time_limit),
+            ..Default::default()
+        }
+    }
+
+    /// The map's blocked regions together with the obstacles given separately.
+    pub fn all_obstacles(&self) -> Vec<Obstacle> {
+        let mut obstacles = self.map.as_ref().map_or(vec![], |map| map.blocked.clone());
+        obstacles.extend_from_slice(&self.obstacles);
+        obstacles
+    }
 }
 
 #[derive(clap::Parser, Debug)]
@@ -29,4 +311,79 @@ pub enum Mode {
         #[arg()]
         recording: PathBuf,
     },
+    /// Simulate a battle twice (or once against a recorded replay) and
+    /// report the first point where the event streams diverge. The data
+    /// directory is ignored, so characters start without their data
+    Verify {
+        #[arg(long = "replay")]
+        replay: Option<PathBuf>,
+        #[clap(flatten)]
+        battle_configuration: BattleConfiguration,
+    },
+}
+
+impl Cli {
+    /// Rejects options that clap accepts but the mode would ignore
+    pub fn validate(&self) -> Result<(), String> {
+        if let Mode::Verify {
+            battle_configuration,
+            ..
+        } = &self.mode
+        {
+            if battle_configuration.record.is_some() {
+                return Err("--record cannot be used when verifying".to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod cli {
+        use super::*;
+
+        #[test]
+        fn verify_rejects_recording() {
+            let cli =
+                Cli::try_parse_from(["luarena-rs", "verify", "-c", "characters/kai"]).unwrap();
+            assert!(cli.validate().is_ok());
+            let cli = Cli::try_parse_from([
+                "luarena-rs",
+                "verify",
+                "-c",
+                "characters/kai",
+                "--record",
+                "battle.replay",
+            ])
+            .unwrap();
+            assert!(cli.validate().is_err());
+        }
+    }
+
+    mod character_entry {
+        use super::*;
+
+        #[test]
+        fn team_is_optional() {
+            let entry: CharacterEntry = "characters/kai".parse().unwrap();
+            assert_eq!(entry.dir, PathBuf::from("characters/kai"));
+            assert_eq!(entry.team, None);
+        }
+
+        #[test]
+        fn team_follows_the_last_at_sign() {
+            let entry: CharacterEntry = "characters/k@i@red".parse().unwrap();
+            assert_eq!(entry.dir, PathBuf::from("characters/k@i"));
+            assert_eq!(entry.team, Some("red".to_string()));
+            assert_eq!(entry.to_string(), "characters/k@i@red");
+        }
+
+        #[test]
+        fn team_must_not_be_empty() {
+            assert!("characters/kai@".parse::<CharacterEntry>().is_err());
+        }
+    }
 }
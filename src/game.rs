@@ -1,24 +1,32 @@
 use core::fmt;
-use std::collections::HashMap;
-use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{mpsc, Arc};
 
-use rand::rngs::ThreadRng;
-use rand::Rng;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
 
-use crate::character::{self, Character, MovementDirection};
-use crate::config::BattleConfiguration;
+use crate::character::{self, AttackKind, Character, MovementDirection, WallSide, Winner};
+use crate::config::{BattleConfiguration, CharacterEntry, CrashPolicy, MessageScope, TimeoutRule};
+use crate::map::{Hazard, LoadMapError};
 use crate::math_utils::{self, Point, Sector, HALF_PI};
-use crate::settings::*;
+use crate::obstacle::Obstacle;
+use crate::replay::{self, ReplayError, ReplayWriter};
+use crate::settings::Rules;
 
-#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct AttackId(usize);
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Attack {
     pub id: AttackId,
+    pub kind: AttackKind,
     pub pos: Point,
+    /// Where the attack was created, to tell when it is out of range
+    pub origin: Point,
     pub owner: character::Meta,
     pub heading: f32,
     pub velocity: f32,
@@ -42,15 +50,15 @@ impl AttackIds {
 
 pub enum RoundState {
     Ongoing,
-    Won(character::Meta),
+    Won(Winner),
     Draw,
 }
 
-#[derive(Debug, Clone, Copy)]
-pub struct Round(u16);
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Round(pub u16);
 
-#[derive(Debug, Clone, Copy)]
-pub struct Tick(u32);
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Tick(pub u32);
 
 impl Tick {
     pub fn advance(&mut self) {
@@ -61,11 +69,23 @@ impl Tick {
 pub struct Game {
     tick: Tick,
     round: Round,
-    characters: HashMap<character::Meta, character::State>,
-    impls: HashMap<character::Meta, Character>,
+    // Ordered maps, so that iterating over characters is the same in every
+    // run, which is needed for deterministic battles
+    characters: BTreeMap<character::Meta, character::State>,
+    impls: BTreeMap<character::Meta, Character>,
+    /// Created when the first wasm character is added
+    components: Option<character::wasm::ComponentCache>,
     attacks: Vec<Attack>,
     round_state: RoundState,
+    team_rounds_won: BTreeMap<String, u32>,
     attack_ids: AttackIds,
+    seed: u64,
+    rng: StdRng,
+    rules: Rules,
+    obstacles: Vec<Obstacle>,
+    spawn_points: Vec<Point>,
+    hazards: Vec<Hazard>,
+    configuration: BattleConfiguration,
 }
 
 #[derive(Debug)]
@@ -84,46 +104,115 @@ impl From<mlua::Error> for AddCharacterError {
 }
 
 impl Game {
-    pub fn new() -> Game {
+    pub fn new(configuration: BattleConfiguration, seed: u64) -> Game {
         Self {
             tick: Tick(0),
             round: Round(1),
-            characters: HashMap::new(),
-            impls: HashMap::new(),
+            characters: BTreeMap::new(),
+            impls: BTreeMap::new(),
+            components: None,
             attacks: vec![],
             attack_ids: AttackIds::new(),
             round_state: RoundState::Ongoing,
+            team_rounds_won: BTreeMap::new(),
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+            rules: configuration.effective_rules(),
+            obstacles: configuration.all_obstacles(),
+            spawn_points: configuration
+                .map
+                .as_ref()
+                .map_or(vec![], |map| map.spawn_points.clone()),
+            hazards: configuration
+                .map
+                .as_ref()
+                .map_or(vec![], |map| map.hazards.clone()),
+            configuration,
         }
     }
 
-    pub fn with_characters(character_dirs: &[PathBuf]) -> Result<Self, AddCharacterError> {
-        let mut game = Self::new();
-        game.add_characters(character_dirs)?;
+    pub fn from_battle_configuration(
+        battle_configuration: &BattleConfiguration,
+    ) -> Result<Self, GameError> {
+        if let Some(map) = &battle_configuration.map {
+            map.validate_spawn_points(
+                &battle_configuration.effective_rules(),
+                &battle_configuration.all_obstacles(),
+            )?;
+        }
+        let seed = battle_configuration.seed.unwrap_or_else(|| {
+            let seed = rand::random();
+            println!("Using random seed {seed}");
+            seed
+        });
+        let mut game = Self::new(battle_configuration.clone(), seed);
+        game.add_characters(&battle_configuration.characters)?;
         Ok(game)
     }
 
-    fn add_characters(&mut self, character_dirs: &[PathBuf]) -> Result<(), AddCharacterError> {
-        for dir in character_dirs.iter() {
-            self.add_character(dir)?;
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn rules(&self) -> &Rules {
+        &self.rules
+    }
+
+    fn add_characters(&mut self, entries: &[CharacterEntry]) -> Result<(), AddCharacterError> {
+        for entry in entries.iter() {
+            self.add_character(&entry.dir, entry.team.clone())?;
         }
         Ok(())
     }
 
-    fn add_character(&mut self, character_dir: &Path) -> Result<(), AddCharacterError> {
+    fn add_character(
+        &mut self,
+        character_dir: &Path,
+        team: Option<String>,
+    ) -> Result<(), AddCharacterError> {
         let mut meta = character::Meta::from_toml_file(&character_dir.join("meta.toml"))
             .map_err(|e| AddCharacterError(e.0))?;
-        let character_state = character::State::new();
-        if self.impls.contains_key(&meta) {
+        meta.team = team;
+        if let Some(team) = &meta.team {
+            self.team_rounds_won.insert(team.clone(), 0);
+        }
+        let character_state = character::State::new(self.rules.initial_hp);
+        // Instances are counted regardless of the team, which keeps
+        // `Meta::unique_id` unique
+        while self
+            .impls
+            .keys()
+            .any(|other| other.id == meta.id && other.instance == meta.instance)
+        {
             meta.instance += 1;
         }
+        let options = character::LoadOptions {
+            seed: character_seed(self.seed, &meta.unique_id()),
+            ..self.configuration.load_options()
+        };
         let extension = meta.entrypoint.extension().and_then(|s| s.to_str());
         let implementation = match extension {
-            Some("lua") => character::lua::LuaImpl::load(character_dir, &meta)
+            // Characters with a command may be written in any language
+            _ if meta.command.is_some() => {
+                character::process::ProcessImpl::load(character_dir, &meta, &options)
+                    .map_err(|e| AddCharacterError(e.message))
+                    .map(|character_impl| Box::new(character_impl) as Box<dyn character::Impl>)?
+            }
+            Some("lua") => character::lua::LuaImpl::load(character_dir, &meta, &options)
                 .map_err(|e| AddCharacterError(e.to_string()))
                 .map(|character_impl| Box::new(character_impl) as Box<dyn character::Impl>)?,
-            Some("wasm") => character::wasm::WasmImpl::load(character_dir, &meta)
-                .map_err(|e| AddCharacterError(e.message))
-                .map(|character_impl| Box::new(character_impl) as Box<dyn character::Impl>)?,
+            Some("wasm") => {
+                if self.components.is_none() {
+                    let components =
+                        character::wasm::ComponentCache::new(&self.configuration.wasm_cache)
+                            .map_err(|e| AddCharacterError(e.to_string()))?;
+                    self.components = Some(components);
+                }
+                let components = self.components.as_mut().unwrap();
+                character::wasm::WasmImpl::load(character_dir, &meta, &options, components)
+                    .map_err(|e| AddCharacterError(e.message))
+                    .map(|character_impl| Box::new(character_impl) as Box<dyn character::Impl>)?
+            }
             Some(unexpected) => {
                 return Err(AddCharacterError(format!(
                     "Unexpected entrypoint extension: {unexpected}"
@@ -141,26 +230,41 @@ impl Game {
         Ok(())
     }
 
-    pub fn init_round(&mut self, round: Round, event_manager: &mut EventManager) {
+    pub fn init_round(
+        &mut self,
+        round: Round,
+        event_manager: &mut EventManager,
+    ) -> Result<(), PlacementError> {
         self.tick = Tick(0);
         self.round = round;
         self.round_state = RoundState::Ongoing;
         self.attacks = vec![];
-        let mut characters = HashMap::new();
-        let rng = rand::thread_rng();
-        let randomized_positions = random_positions(self.characters.len(), rng);
+        let mut characters = vec![];
+        let randomized_positions = random_positions(
+            self.characters.len(),
+            &self.rules,
+            &self.obstacles,
+            &self.spawn_points,
+            &mut self.rng,
+        )?;
         for ((meta, character_state), p) in
             self.characters.iter_mut().zip(randomized_positions.iter())
         {
             // FIXME: it would be nice to change state later (as with the rest),
             // but that creates problems with the check for "round over"
-            character_state.reset(p.clone());
-            characters.insert(meta.clone(), p.clone());
+            character_state.reset(p.clone(), self.rules.initial_hp);
+            if self.impls[meta].disqualified {
+                character_state.hp = 0.0;
+                continue;
+            }
+            characters.push((meta.clone(), p.clone()));
         }
         event_manager.init_round(round, characters);
         for (_, character) in self.impls.iter_mut() {
             character.intent = Default::default();
+            character.crashed = false;
         }
+        Ok(())
     }
 
     pub fn living_characters(&self) -> impl Iterator<Item = (&character::Meta, &character::State)> {
@@ -175,6 +279,14 @@ impl Game {
             .1
     }
 
+    /// The damage the given attack deals when it hits
+    fn attack_damage(&self, id: &AttackId) -> f32 {
+        self.attacks
+            .iter()
+            .find(|attack| attack.id == *id)
+            .map_or(0.0, |attack| self.rules.attacks.get(attack.kind).damage)
+    }
+
     pub fn attack(&mut self, id: &AttackId) -> &mut Attack {
         self.attacks
             .iter_mut()
@@ -187,37 +299,109 @@ impl Game {
     }
 
     pub fn print_stats(&self) {
-        println!("  Rounds won:");
-        for (meta, character_state) in self.characters.iter() {
-            let stats = &character_state.stats;
+        let weights = &self.rules.score;
+        let mut by_score: Vec<_> = self
+            .characters
+            .iter()
+            .map(|(meta, character_state)| {
+                let stats = &character_state.stats;
+                (meta, stats, stats.score(weights))
+            })
+            .collect();
+        by_score.sort_by(|(_, _, a), (_, _, b)| b.total_cmp(a));
+        println!("  Scores:");
+        for (meta, stats, score) in by_score.iter() {
             println!(
-                "    {}: {} rounds won",
+                "    {}: {score:.0} points ({} rounds won, {:.0} damage dealt, {:.0} damage taken, {} kills, {} survival points, {}/{} shots hit, {} ticks survived, {} crashes)",
                 meta.display_name(),
-                stats.rounds_won
+                stats.rounds_won,
+                stats.damage_dealt,
+                stats.damage_taken,
+                stats.kills,
+                stats.survival_points,
+                stats.shots_hit,
+                stats.shots_fired,
+                stats.ticks_survived,
+                stats.crashes,
             );
         }
+        if !self.team_rounds_won.is_empty() {
+            println!("  Scores by team:");
+            for (team, rounds_won) in self.team_rounds_won.iter() {
+                let score: f32 = by_score
+                    .iter()
+                    .filter(|(meta, _, _)| meta.team.as_ref() == Some(team))
+                    .map(|(_, _, score)| score)
+                    .sum();
+                println!("    {team}: {score:.0} points ({rounds_won} rounds won)");
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct PlacementError(pub String);
+
+impl fmt::Display for PlacementError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
     }
 }
 
-fn random_positions(n: usize, mut rng: ThreadRng) -> Vec<Point> {
+/// How often a random position is tried for each character before giving up
+const MAX_PLACEMENT_ATTEMPTS: usize = 10_000;
+
+/// Picks the starting positions of `n` characters. The given spawn points are
+/// used first, in random order; if there are not enough of them the remaining
+/// characters are placed randomly, which fails if the arena is too crowded.
+fn random_positions(
+    n: usize,
+    rules: &Rules,
+    obstacles: &[Obstacle],
+    spawn_points: &[Point],
+    rng: &mut impl Rng,
+) -> Result<Vec<Point>, PlacementError> {
     let wall_dist = 20.0;
-    let min = CHARACTER_RADIUS + wall_dist;
-    let max_x = WIDTH as f32 - CHARACTER_RADIUS - wall_dist;
-    let max_y = HEIGHT as f32 - CHARACTER_RADIUS - wall_dist;
-    let mut positions = vec![];
-    for _i in 0..n {
+    let min = rules.character_radius + wall_dist;
+    let max_x = rules.width as f32 - rules.character_radius - wall_dist;
+    let max_y = rules.height as f32 - rules.character_radius - wall_dist;
+    let mut positions = spawn_points.to_vec();
+    positions.shuffle(rng);
+    positions.truncate(n);
+    if positions.len() < n && (min >= max_x || min >= max_y) {
+        return Err(PlacementError(format!(
+            "The arena ({}x{}) is too small to place characters in",
+            rules.width, rules.height
+        )));
+    }
+    for _i in positions.len()..n {
+        let mut attempts = 0;
         loop {
+            if attempts == MAX_PLACEMENT_ATTEMPTS {
+                return Err(PlacementError(format!(
+                    "Found no free starting position for {} of {n} characters",
+                    n - positions.len()
+                )));
+            }
+            attempts += 1;
             let new_p = Point {
                 x: rng.gen_range(min..max_x) as f32,
                 y: rng.gen_range(min..max_y) as f32,
             };
-            if !positions.iter().any(|p| characters_collide(p, &new_p)) {
+            let blocked = obstacles
+                .iter()
+                .any(|obstacle| obstacle.overlaps_circle(&new_p, rules.character_radius));
+            if !blocked
+                && !positions
+                    .iter()
+                    .any(|p| characters_collide(p, &new_p, rules))
+            {
                 positions.push(new_p);
                 break;
             }
         }
     }
-    positions
+    Ok(positions)
 }
 
 fn reduce_commands(commands: &mut Vec<character::Command>) {
@@ -225,14 +409,29 @@ fn reduce_commands(commands: &mut Vec<character::Command>) {
     commands.sort_by_key(|cmd| cmd.index());
 }
 
-fn valid_position(p: &Point) -> bool {
-    p.x >= CHARACTER_RADIUS
-        && p.x <= WIDTH as f32 - CHARACTER_RADIUS
-        && p.y >= CHARACTER_RADIUS
-        && p.y <= HEIGHT as f32 - CHARACTER_RADIUS
+fn inside_safe_zone(p: &Point, margin: f32, rules: &Rules) -> bool {
+    p.x >= margin
+        && p.x <= rules.width as f32 - margin
+        && p.y >= margin
+        && p.y <= rules.height as f32 - margin
+}
+
+fn hit_wall(p: &Point, rules: &Rules) -> Option<WallSide> {
+    let radius = rules.character_radius;
+    if p.y < radius {
+        Some(WallSide::Top)
+    } else if p.x > rules.width as f32 - radius {
+        Some(WallSide::Right)
+    } else if p.y > rules.height as f32 - radius {
+        Some(WallSide::Bottom)
+    } else if p.x < radius {
+        Some(WallSide::Left)
+    } else {
+        None
+    }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Delta {
     pub value: Point,
 }
@@ -244,11 +443,11 @@ impl Delta {
 }
 
 // TODO: use struct variants maybe
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum GameEvent {
     Tick(Tick),
-    RoundStarted(Round, HashMap<character::Meta, Point>),
-    RoundEnded(Option<character::Meta>),
+    RoundStarted(Round, Vec<(character::Meta, Point)>),
+    RoundEnded(Option<Winner>),
     CharacterHeadTurned(character::Meta, f32),
     CharacterArmsTurned(character::Meta, f32),
     Hit(AttackId, character::Meta, character::Meta, Point),
@@ -259,21 +458,38 @@ pub enum GameEvent {
     CharacterPositionUpdated(character::Meta, Delta),
     CharacterTurned(character::Meta, f32),
     CharacterDied(character::Meta),
+    /// A character tried to move into a wall, which is at the given bearing
+    WallHit(character::Meta, WallSide, f32),
+    /// Two characters ran into each other, each taking the given damage
+    CharactersCollided(character::Meta, character::Meta, f32),
+    /// During sudden death: the distance of the safe zone to the walls
+    ArenaShrunk(f32),
+    ArenaDamage(character::Meta, f32),
+    MessageSent(character::Meta, character::Message),
+    /// The character skipped too many turns and is out of the battle
+    CharacterDisqualified(character::Meta),
+    /// The character crashed and is out of the current round
+    CharacterRemoved(character::Meta),
 }
 
-fn clamp_turn_angle(angle: f32) -> f32 {
-    math_utils::clamp(angle, -ANGLE_OF_ACTION, ANGLE_OF_ACTION)
+fn clamp_turn_angle(angle: f32, rules: &Rules) -> f32 {
+    math_utils::clamp(angle, -rules.angle_of_action, rules.angle_of_action)
 }
 
 fn transition_characters(game: &mut Game, event_manager: &mut EventManager) {
     // TODO: is a HashMap appropriate here? is there a smarter way?
     let mut next_positions: HashMap<character::Meta, (Delta, Point)> = HashMap::new();
+    let rules = &game.rules;
     for (meta, character_state) in game.living_characters() {
         let character = game.impls.get(&meta).unwrap();
-        let delta = math_utils::clamp(character.intent.turn_angle, -MAX_TURN_RATE, MAX_TURN_RATE);
+        let delta = math_utils::clamp(
+            character.intent.turn_angle,
+            -rules.max_turn_rate,
+            rules.max_turn_rate,
+        );
         event_manager.record(GameEvent::CharacterTurned(meta.clone(), delta));
         let heading = math_utils::normalize_absolute_angle(character_state.heading + delta);
-        let velocity = f32::min(character.intent.distance, MAX_VELOCITY);
+        let velocity = f32::min(character.intent.distance, rules.max_velocity);
         let dir_heading = match character.intent.direction {
             MovementDirection::Forward => 0.0,
             MovementDirection::Backward => math_utils::PI,
@@ -286,43 +502,117 @@ fn transition_characters(game: &mut Game, event_manager: &mut EventManager) {
         let delta = Delta::new(Point { x: dx, y: dy });
         let pos = &character_state.pos;
         let next_pos = pos.add(&delta.value);
-        if valid_position(&next_pos) {
-            next_positions.insert(meta.clone(), (delta, next_pos));
-        } else {
-            next_positions.insert(meta.clone(), (Delta::new(Point::zero()), pos.clone()));
+        match hit_wall(&next_pos, rules) {
+            None if blocked_by_obstacle(&game.obstacles, pos, &next_pos, rules) => {
+                next_positions.insert(meta.clone(), (Delta::new(Point::zero()), pos.clone()));
+            }
+            None => {
+                next_positions.insert(meta.clone(), (delta, next_pos));
+            }
+            Some(side) => {
+                next_positions.insert(meta.clone(), (Delta::new(Point::zero()), pos.clone()));
+                let bearing = math_utils::normalize_relative_angle(side.angle() - heading);
+                event_manager.record(GameEvent::WallHit(meta.clone(), side, bearing));
+                let wall_damage = game.configuration.wall_damage;
+                if wall_damage > 0.0 {
+                    event_manager.record(GameEvent::ArenaDamage(meta.clone(), wall_damage));
+                    record_death_if_killed(event_manager, meta, character_state.hp, game);
+                }
+            }
         };
 
         transition_heads(
             &meta,
             character_state,
             character.intent.turn_head_angle,
+            rules,
             event_manager,
         );
         transition_arms(
             &meta,
             character_state,
             character.intent.turn_arms_angle,
+            rules,
             event_manager,
         );
     }
 
+    let collisions = resolve_collisions(game, &mut next_positions);
     for (meta, _) in game.living_characters() {
-        let (delta, next) = next_positions.get(&meta).unwrap();
-        let mut collides = false;
-        for (other_meta, (_, other_next)) in next_positions.iter() {
-            if meta != other_meta {
-                if characters_collide(&next, &other_next) {
-                    // TODO: collision event
-                    collides = true;
+        let (delta, _) = next_positions.get(meta).unwrap();
+        event_manager.record(GameEvent::CharacterPositionUpdated(
+            meta.clone(),
+            delta.clone(),
+        ));
+    }
+
+    for (meta, other_meta) in collisions {
+        let ram_damage = if meta.allied_with(other_meta) && !game.configuration.friendly_fire {
+            0.0
+        } else {
+            game.configuration.ram_damage
+        };
+        event_manager.record(GameEvent::CharactersCollided(
+            meta.clone(),
+            other_meta.clone(),
+            ram_damage,
+        ));
+        for victim in [meta, other_meta] {
+            record_death_if_killed(event_manager, victim, game.characters[victim].hp, game);
+        }
+    }
+}
+
+/// Whether moving from `pos` to `next_pos` would take a character into an
+/// obstacle. Characters already overlapping one may still move out of it.
+fn blocked_by_obstacle(
+    obstacles: &[Obstacle],
+    pos: &Point,
+    next_pos: &Point,
+    rules: &Rules,
+) -> bool {
+    let radius = rules.character_radius;
+    obstacles.iter().any(|obstacle| {
+        obstacle.overlaps_circle(next_pos, radius) && !obstacle.overlaps_circle(pos, radius)
+    })
+}
+
+/// Stops characters from moving into each other, and returns all pairs of
+/// characters that collided. Characters that are already touching may still
+/// move apart.
+fn resolve_collisions<'a>(
+    game: &'a Game,
+    next_positions: &mut HashMap<character::Meta, (Delta, Point)>,
+) -> Vec<(&'a character::Meta, &'a character::Meta)> {
+    let characters: Vec<_> = game.living_characters().collect();
+    let mut collisions = vec![];
+    // Blocking a character can make another one run into it, so repeat until
+    // nothing changes anymore
+    loop {
+        let mut changed = false;
+        for (i, (meta, character_state)) in characters.iter().enumerate() {
+            for (other_meta, other_state) in characters.iter().skip(i + 1) {
+                let next = &next_positions[*meta].1;
+                let other_next = &next_positions[*other_meta].1;
+                let approaching =
+                    next.dist(other_next) < character_state.pos.dist(&other_state.pos);
+                if approaching && characters_collide(next, other_next, &game.rules) {
+                    next_positions.insert(
+                        (*meta).clone(),
+                        (Delta::new(Point::zero()), character_state.pos.clone()),
+                    );
+                    next_positions.insert(
+                        (*other_meta).clone(),
+                        (Delta::new(Point::zero()), other_state.pos.clone()),
+                    );
+                    collisions.push((*meta, *other_meta));
+                    changed = true;
                 }
             }
         }
-        let event = if !collides {
-            GameEvent::CharacterPositionUpdated(meta.clone(), delta.clone())
-        } else {
-            GameEvent::CharacterPositionUpdated(meta.clone(), Delta::new(Point::zero()))
-        };
-        event_manager.record(event);
+        if !changed {
+            return collisions;
+        }
     }
 }
 
@@ -330,11 +620,16 @@ fn transition_heads(
     meta: &character::Meta,
     character_state: &character::State,
     turn_angle: f32,
+    rules: &Rules,
     event_manager: &mut EventManager,
 ) {
-    let delta = math_utils::clamp(turn_angle, -MAX_HEAD_TURN_RATE, MAX_HEAD_TURN_RATE);
+    let delta = math_utils::clamp(
+        turn_angle,
+        -rules.max_head_turn_rate,
+        rules.max_head_turn_rate,
+    );
     let current_heading = character_state.head_heading;
-    let effective_delta = clamp_turn_angle(current_heading + delta) - current_heading;
+    let effective_delta = clamp_turn_angle(current_heading + delta, rules) - current_heading;
     event_manager.record(GameEvent::CharacterHeadTurned(
         meta.clone(),
         effective_delta,
@@ -345,19 +640,37 @@ fn transition_arms(
     meta: &character::Meta,
     character_state: &character::State,
     turn_angle: f32,
+    rules: &Rules,
     event_manager: &mut EventManager,
 ) {
-    let delta = math_utils::clamp(turn_angle, -MAX_ARMS_TURN_RATE, MAX_ARMS_TURN_RATE);
+    let delta = math_utils::clamp(
+        turn_angle,
+        -rules.max_arms_turn_rate,
+        rules.max_arms_turn_rate,
+    );
     let current_heading = character_state.arms_heading;
-    let effective_delta = clamp_turn_angle(current_heading + delta) - current_heading;
+    let effective_delta = clamp_turn_angle(current_heading + delta, rules) - current_heading;
     event_manager.record(GameEvent::CharacterArmsTurned(
         meta.clone(),
         effective_delta,
     ));
 }
 
-fn characters_collide(p: &Point, q: &Point) -> bool {
-    p.dist(q) <= 2.0 * (CHARACTER_RADIUS as f32)
+fn characters_collide(p: &Point, q: &Point, rules: &Rules) -> bool {
+    p.dist(q) <= 2.0 * rules.character_radius
+}
+
+fn receives_message(
+    scope: MessageScope,
+    sender: &character::Meta,
+    receiver: &character::Meta,
+) -> bool {
+    sender != receiver
+        && match scope {
+            MessageScope::All => true,
+            MessageScope::Team => sender.allied_with(receiver),
+            MessageScope::SameId => sender.id == receiver.id,
+        }
 }
 
 fn game_events_to_character_events(
@@ -365,6 +678,7 @@ fn game_events_to_character_events(
     character_state: &character::State,
     intent: &character::Intent,
     game_events: &[GameEvent],
+    message_scope: MessageScope,
 ) -> Vec<character::Event> {
     let mut character_events = Vec::new();
     for event in game_events.iter() {
@@ -378,11 +692,11 @@ fn game_events_to_character_events(
             GameEvent::RoundStarted(round, _) => {
                 character_events.push(character::Event::RoundStarted(round.0));
             }
-            GameEvent::RoundEnded(opt_meta) => {
-                character_events.push(character::Event::RoundEnded(opt_meta.clone()));
-                match opt_meta {
-                    Some(winner_meta) => {
-                        if winner_meta == meta {
+            GameEvent::RoundEnded(opt_winner) => {
+                character_events.push(character::Event::RoundEnded(opt_winner.clone()));
+                match opt_winner {
+                    Some(winner) => {
+                        if winner.includes(meta) {
                             character_events.push(character::Event::RoundWon);
                         }
                     }
@@ -407,6 +721,30 @@ fn game_events_to_character_events(
             GameEvent::AttackAdvanced(_, _) => {}
             GameEvent::AttackMissed(_) => {}
             GameEvent::AttackCreated(_, _) => {}
+            GameEvent::CharactersCollided(first, second, _) => {
+                if meta == first {
+                    character_events.push(character::Event::Collision(second.clone()));
+                } else if meta == second {
+                    character_events.push(character::Event::Collision(first.clone()));
+                }
+            }
+            GameEvent::WallHit(wall_meta, side, bearing) => {
+                if meta == wall_meta {
+                    character_events.push(character::Event::HitWall(*side, *bearing));
+                }
+            }
+            GameEvent::ArenaShrunk(_) => {}
+            GameEvent::ArenaDamage(_, _) => {}
+            GameEvent::CharacterDisqualified(_) => {}
+            GameEvent::CharacterRemoved(_) => {}
+            GameEvent::MessageSent(sender, message) => {
+                if receives_message(message_scope, sender, meta) {
+                    character_events.push(character::Event::Message(
+                        sender.name.clone(),
+                        message.clone(),
+                    ));
+                }
+            }
             GameEvent::CharacterDied(deceased_meta) => {
                 let death_event = if meta == deceased_meta {
                     character::Event::Death
@@ -426,13 +764,134 @@ fn can_spot(
     target: &Point,
     character_radius: f32,
     angle_of_vision: f32,
+    obstacles: &[Obstacle],
 ) -> bool {
     let view_sector = Sector::new(view_angle, angle_of_vision / 2.0);
     let d = origin.dist(target);
     let alpha = f32::atan(character_radius / d);
     let angle = math_utils::normalize_absolute_angle(math_utils::angle_between(origin, target));
     let target_sector = Sector::new(angle, alpha);
+    // Only the line of sight to the target's center is checked, so a
+    // character that is partially hidden cannot be seen
     view_sector.overlaps(&target_sector)
+        && !obstacles
+            .iter()
+            .any(|obstacle| obstacle.intersects_segment(origin, target))
+}
+
+fn seen_character(
+    observer: &character::State,
+    meta: &character::Meta,
+    state: &character::State,
+    events: &[GameEvent],
+) -> character::SeenCharacter {
+    let velocity = events
+        .iter()
+        .find_map(|event| match event {
+            GameEvent::CharacterPositionUpdated(id, delta) if id == meta => {
+                Some(delta.value.clone())
+            }
+            _ => None,
+        })
+        .unwrap_or(Point::zero());
+    let angle = math_utils::angle_between(&observer.pos, &state.pos);
+    character::SeenCharacter {
+        id: meta.unique_id(),
+        name: meta.name.clone(),
+        pos: state.pos.clone(),
+        heading: state.heading,
+        velocity,
+        distance: observer.pos.dist(&state.pos),
+        bearing: math_utils::normalize_relative_angle(angle - observer.heading),
+        hp: state.hp,
+    }
+}
+
+/// The sounds enemies made in the current step within the hearing radius.
+/// Every source is heard at most once per kind.
+fn heard_sounds(
+    meta: &character::Meta,
+    character_state: &character::State,
+    living: &[(character::Meta, character::State)],
+    events: &[GameEvent],
+    rules: &Rules,
+) -> Vec<character::Event> {
+    let mut sources: Vec<(character::SoundKind, &character::Meta)> = Vec::new();
+    for event in events {
+        let sound = match event {
+            GameEvent::AttackCreated(owner, _) if owner != meta && !meta.allied_with(owner) => {
+                (character::SoundKind::Attack, owner)
+            }
+            GameEvent::CharacterPositionUpdated(other, delta)
+                if other != meta && !meta.allied_with(other) && delta.value != Point::zero() =>
+            {
+                (character::SoundKind::Movement, other)
+            }
+            _ => continue,
+        };
+        if !sources.contains(&sound) {
+            sources.push(sound);
+        }
+    }
+    sources
+        .into_iter()
+        .filter_map(|(kind, source)| {
+            let (_, source_state) = living.iter().find(|(other, _)| other == source)?;
+            if character_state.pos.dist(&source_state.pos) > rules.hearing_radius {
+                return None;
+            }
+            let angle = math_utils::angle_between(&character_state.pos, &source_state.pos);
+            let bearing = math_utils::normalize_relative_angle(angle - character_state.heading);
+            let coarse_bearing =
+                (bearing / rules.hearing_precision).round() * rules.hearing_precision;
+            Some(character::Event::Sound(
+                kind,
+                math_utils::normalize_relative_angle(coarse_bearing),
+            ))
+        })
+        .collect()
+}
+
+/// Derives a character's random seed from the battle's seed with FNV-1a, so
+/// that it is the same on every machine and for every build.
+fn character_seed(seed: u64, unique_id: &str) -> u64 {
+    unique_id
+        .bytes()
+        .fold(seed ^ 0xcbf2_9ce4_8422_2325, |hash, b| {
+            (hash ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+        })
+}
+
+/// Deals with a character failing to handle its events. Its commands of the
+/// turn are dropped either way, and a crashed character also stops doing what
+/// it was told before.
+fn handle_character_error(
+    meta: &character::Meta,
+    character: &mut Character,
+    character_state: &mut character::State,
+    error: character::EventError,
+    crash_policy: CrashPolicy,
+) {
+    let name = meta.display_name();
+    match error.kind {
+        character::EventErrorKind::BudgetExceeded => {
+            character::log_msg(&name, &format!("{error}, skipping turn"));
+            character.skipped_turns += 1;
+            character.turn_skipped = true;
+        }
+        character::EventErrorKind::MemoryExceeded => {
+            character::log_msg(&name, &error.to_string());
+            character.memory_exceeded = true;
+        }
+        character::EventErrorKind::InvalidCommand | character::EventErrorKind::Other => {
+            character::log_msg(&name, &format!("crashed: {error}"));
+            character_state.stats.crashes += 1;
+            character.intent = Default::default();
+            if crash_policy == CrashPolicy::Remove {
+                character.crashed = true;
+            }
+        }
+    }
 }
 
 fn dispatch_character_events(
@@ -452,13 +911,17 @@ fn create_attacks(game: &mut Game, event_manager: &mut EventManager) {
             continue;
         }
         let character = game.impls.get(meta).unwrap();
-        let will_attack = character.intent.attack && character_state.attack_cooldown == 0;
-        if will_attack {
+        let Some(kind) = character.intent.attack else {
+            continue;
+        };
+        if character_state.attack_cooldown == 0 {
             let attack = Attack {
                 id: game.attack_ids.next(),
+                kind,
                 owner: meta.clone(),
                 pos: character_state.pos.clone(),
-                velocity: 2.5,
+                origin: character_state.pos.clone(),
+                velocity: game.rules.attacks.get(kind).velocity,
                 heading: character_state.effective_arms_heading(),
             };
             event_manager.record(GameEvent::AttackCreated(meta.clone(), attack));
@@ -466,17 +929,21 @@ fn create_attacks(game: &mut Game, event_manager: &mut EventManager) {
     }
 }
 
-fn inside_arena(p: &Point) -> bool {
-    p.x >= 0.0 && p.x <= WIDTH as f32 && p.y >= 0.0 && p.y <= HEIGHT as f32
+fn inside_arena(p: &Point, rules: &Rules) -> bool {
+    p.x >= 0.0 && p.x <= rules.width as f32 && p.y >= 0.0 && p.y <= rules.height as f32
 }
 
 fn attack_hits_character<'a>(
     attack: &Attack,
     mut characters: impl Iterator<Item = (&'a character::Meta, &'a character::State)>,
+    rules: &Rules,
+    friendly_fire: bool,
 ) -> Option<(&'a character::Meta, &'a character::State)> {
+    // Without friendly fire, attacks fly right through allies
     characters.find(|(meta, character)| {
         **meta != attack.owner
-            && attack.pos.dist(&character.pos) <= ATTACK_RADIUS + CHARACTER_RADIUS as f32
+            && (friendly_fire || !meta.allied_with(&attack.owner))
+            && attack.pos.dist(&character.pos) <= rules.attack_radius + rules.character_radius
     })
 }
 
@@ -490,6 +957,7 @@ pub struct EventManager {
     current_events: StepEvents,
     all_events: Vec<StepEvents>,
     mode: EventRemembrance,
+    replay_writer: Option<ReplayWriter>,
 }
 
 impl EventManager {
@@ -498,25 +966,39 @@ impl EventManager {
             current_events: StepEvents::new(),
             all_events: vec![],
             mode,
+            replay_writer: None,
+        }
+    }
+
+    pub fn with_battle_configuration(
+        mode: EventRemembrance,
+        battle_configuration: &BattleConfiguration,
+        game: &Game,
+    ) -> Result<EventManager, GameError> {
+        let mut event_manager = Self::new(mode);
+        if let Some(path) = &battle_configuration.record {
+            let mut battle_configuration = battle_configuration.clone();
+            battle_configuration.seed = Some(game.seed());
+            battle_configuration.rules = Some(game.rules().clone());
+            let header = replay::Header {
+                version: replay::FORMAT_VERSION,
+                battle_configuration,
+            };
+            event_manager.replay_writer = Some(ReplayWriter::create(path, &header)?);
         }
+        Ok(event_manager)
     }
 
     fn remember_events(&self) -> bool {
         self.mode == EventRemembrance::Remember
     }
 
-    pub fn init_round(&mut self, round: Round, characters: HashMap<character::Meta, Point>) {
-        if self.remember_events() {
-            self.all_events.push(self.current_events.clone());
-        }
+    pub fn init_round(&mut self, round: Round, characters: Vec<(character::Meta, Point)>) {
         self.current_events =
             StepEvents::from_slice(&vec![GameEvent::RoundStarted(round, characters)]);
     }
 
     pub fn init_tick(&mut self, tick: Tick) {
-        if self.remember_events() {
-            self.all_events.push(self.current_events.clone());
-        }
         // HACK: find a good solution for the first events in a round
         let tick_event = GameEvent::Tick(tick);
         if tick.0 == 0 {
@@ -533,11 +1015,65 @@ impl EventManager {
     pub fn current_events(&self) -> &StepEvents {
         &self.current_events
     }
+
+    pub fn finish_step(&mut self) -> Result<(), ReplayError> {
+        if self.remember_events() {
+            self.all_events.push(self.current_events.clone());
+        }
+        if let Some(replay_writer) = &mut self.replay_writer {
+            replay_writer.write(&self.current_events)?;
+        }
+        Ok(())
+    }
+
+    pub fn into_events(self) -> Vec<StepEvents> {
+        self.all_events
+    }
+
+    pub fn finish(&mut self) -> Result<(), ReplayError> {
+        if let Some(replay_writer) = &mut self.replay_writer {
+            replay_writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// The damage a character has taken from the events recorded so far in the
+/// current step.
+fn pending_damage(meta: &character::Meta, events: &[GameEvent], game: &Game) -> f32 {
+    events
+        .iter()
+        .map(|event| match event {
+            GameEvent::Hit(attack_id, _, victim, _) if victim == meta => {
+                game.attack_damage(attack_id)
+            }
+            GameEvent::ArenaDamage(victim, damage) if victim == meta => *damage,
+            GameEvent::CharactersCollided(first, second, damage)
+                if first == meta || second == meta =>
+            {
+                *damage
+            }
+            _ => 0.0,
+        })
+        .sum()
 }
 
-// FIXME: should take attack type or even concrete attack
-fn remaining_hp(current_hp: f32) -> f32 {
-    current_hp - ATTACK_DAMAGE
+/// Records the death of a character if the damage recorded so far in the
+/// current step is enough to kill it. Characters taking damage from multiple
+/// sources in one step still only die once.
+fn record_death_if_killed(
+    event_manager: &mut EventManager,
+    meta: &character::Meta,
+    hp: f32,
+    game: &Game,
+) {
+    let events = &event_manager.current_events().events;
+    let already_dead = events
+        .iter()
+        .any(|event| matches!(event, GameEvent::CharacterDied(deceased) if deceased == meta));
+    if !already_dead && hp - pending_damage(meta, events, game) <= 0.0 {
+        event_manager.record(GameEvent::CharacterDied(meta.clone()));
+    }
 }
 
 fn transition_attacks(game: &Game, event_manager: &mut EventManager) {
@@ -548,10 +1084,19 @@ fn transition_attacks(game: &Game, event_manager: &mut EventManager) {
             attack.velocity,
             attack.heading,
         );
-        if inside_arena(&next_pos) {
-            if let Some((meta, character_state)) =
-                attack_hits_character(&attack, game.living_characters())
-            {
+        let hits_obstacle = game
+            .obstacles
+            .iter()
+            .any(|obstacle| obstacle.overlaps_circle(&next_pos, game.rules.attack_radius));
+        let out_of_range =
+            attack.origin.dist(&next_pos) > game.rules.attacks.get(attack.kind).range;
+        if inside_arena(&next_pos, &game.rules) && !hits_obstacle && !out_of_range {
+            if let Some((meta, character_state)) = attack_hits_character(
+                attack,
+                game.living_characters(),
+                &game.rules,
+                game.configuration.friendly_fire,
+            ) {
                 // FIXME: new_pos or old position here?
                 event_manager.record(GameEvent::Hit(
                     attack.id,
@@ -559,9 +1104,7 @@ fn transition_attacks(game: &Game, event_manager: &mut EventManager) {
                     meta.clone(),
                     next_pos,
                 ));
-                if remaining_hp(character_state.hp) <= 0.0 {
-                    event_manager.record(GameEvent::CharacterDied(meta.clone()));
-                }
+                record_death_if_killed(event_manager, meta, character_state.hp, game);
             } else {
                 event_manager.record(GameEvent::AttackAdvanced(attack.id, next_pos));
             }
@@ -571,7 +1114,52 @@ fn transition_attacks(game: &Game, event_manager: &mut EventManager) {
     }
 }
 
+fn transition_arena(game: &Game, event_manager: &mut EventManager) {
+    for (meta, character_state) in game.living_characters() {
+        for hazard in game.hazards.iter() {
+            if hazard
+                .area
+                .overlaps_circle(&character_state.pos, game.rules.character_radius)
+            {
+                event_manager.record(GameEvent::ArenaDamage(meta.clone(), hazard.damage));
+                record_death_if_killed(event_manager, meta, character_state.hp, game);
+            }
+        }
+    }
+
+    let max_ticks = game.configuration.max_ticks;
+    if game.configuration.timeout_rule != TimeoutRule::SuddenDeath || game.tick.0 < max_ticks {
+        return;
+    }
+    let rules = &game.rules;
+    let max_margin = i32::min(rules.width, rules.height) as f32 / 2.0;
+    let margin = f32::min(
+        (game.tick.0 - max_ticks) as f32 * rules.sudden_death_shrink_rate,
+        max_margin,
+    );
+    event_manager.record(GameEvent::ArenaShrunk(margin));
+    for (meta, character_state) in game.living_characters() {
+        if inside_safe_zone(&character_state.pos, margin, rules) {
+            continue;
+        }
+        event_manager.record(GameEvent::ArenaDamage(
+            meta.clone(),
+            rules.sudden_death_damage,
+        ));
+        record_death_if_killed(event_manager, meta, character_state.hp, game);
+    }
+}
+
+fn transition_messages(game: &Game, event_manager: &mut EventManager) {
+    for (meta, _) in game.living_characters() {
+        for message in game.impls[meta].intent.messages.iter() {
+            event_manager.record(GameEvent::MessageSent(meta.clone(), message.clone()));
+        }
+    }
+}
+
 fn advance_game_state(game: &mut Game, events: &[GameEvent]) {
+    let rules = game.rules.clone();
     for event in events {
         match event {
             GameEvent::Tick(_) => {
@@ -580,6 +1168,9 @@ fn advance_game_state(game: &mut Game, events: &[GameEvent]) {
                 // possible again might be better; but then again we could not
                 // as easily add a Lua getter...
                 for character_state in game.characters.values_mut() {
+                    if character_state.alive() {
+                        character_state.stats.ticks_survived += 1;
+                    }
                     let cd = character_state.attack_cooldown;
                     if cd > 0 {
                         character_state.attack_cooldown = cd - 1;
@@ -590,7 +1181,14 @@ fn advance_game_state(game: &mut Game, events: &[GameEvent]) {
             GameEvent::RoundEnded(opt_winner) => match opt_winner {
                 Some(winner) => {
                     game.round_state = RoundState::Won(winner.clone());
-                    game.character_state(winner).stats.rounds_won += 1;
+                    for (meta, character_state) in game.characters.iter_mut() {
+                        if winner.includes(meta) {
+                            character_state.stats.rounds_won += 1;
+                        }
+                    }
+                    if let Winner::Team(team) = winner {
+                        *game.team_rounds_won.entry(team.clone()).or_default() += 1;
+                    }
                 }
                 None => game.round_state = RoundState::Draw,
             },
@@ -613,7 +1211,7 @@ fn advance_game_state(game: &mut Game, events: &[GameEvent]) {
                 character.heading = math_utils::normalize_absolute_angle(heading);
                 let lua_impl = game.character(id);
                 let turn_angle = lua_impl.intent.turn_angle;
-                lua_impl.intent.turn_angle = if turn_angle.abs() < MAX_TURN_RATE {
+                lua_impl.intent.turn_angle = if turn_angle.abs() < rules.max_turn_rate {
                     0.0
                 } else {
                     turn_angle - *delta
@@ -627,7 +1225,7 @@ fn advance_game_state(game: &mut Game, events: &[GameEvent]) {
                 lua_impl.intent.turn_head_angle =
                     // FIXME: (here and elsewhere): we don't need this if we
                     // stop using float equality checks I think
-                    if intended.abs() < MAX_HEAD_TURN_RATE {
+                    if intended.abs() < rules.max_head_turn_rate {
                         0.0
                     } else {
                         intended - *delta
@@ -635,16 +1233,17 @@ fn advance_game_state(game: &mut Game, events: &[GameEvent]) {
             }
             GameEvent::CharacterArmsTurned(id, delta) => {
                 let character = game.character_state(id);
-                character.arms_heading = clamp_turn_angle(character.arms_heading + *delta);
+                character.arms_heading = clamp_turn_angle(character.arms_heading + *delta, &rules);
                 let lua_impl = game.character(id);
                 let intended = lua_impl.intent.turn_arms_angle;
-                lua_impl.intent.turn_arms_angle = if intended.abs() < MAX_ARMS_TURN_RATE {
+                lua_impl.intent.turn_arms_angle = if intended.abs() < rules.max_arms_turn_rate {
                     0.0
                 } else {
                     intended - *delta
                 };
             }
-            GameEvent::Hit(attack_id, _, victim_id, _) => {
+            GameEvent::Hit(attack_id, owner_id, victim_id, _) => {
+                let damage = game.attack_damage(attack_id);
                 if let Some(index) = game
                     .attacks
                     .iter()
@@ -652,7 +1251,12 @@ fn advance_game_state(game: &mut Game, events: &[GameEvent]) {
                 {
                     game.attacks.remove(index);
                 }
-                game.character_state(victim_id).hp -= ATTACK_DAMAGE;
+                let effective = game.character_state(victim_id).take_damage(damage);
+                let owner_stats = &mut game.character_state(owner_id).stats;
+                if !owner_id.allied_with(victim_id) {
+                    owner_stats.shots_hit += 1;
+                    owner_stats.damage_dealt += effective;
+                }
             }
             GameEvent::AttackAdvanced(id, pos) => {
                 let attack = game.attack(id);
@@ -666,80 +1270,219 @@ fn advance_game_state(game: &mut Game, events: &[GameEvent]) {
             GameEvent::AttackCreated(owner, attack) => {
                 game.attacks.push(attack.clone());
                 let character = game.character_state(owner);
-                character.attack_cooldown = ATTACK_COOLDOWN;
+                character.attack_cooldown = rules.attacks.get(attack.kind).cooldown;
+                character.stats.shots_fired += 1;
                 let lua_impl = game.character(owner);
-                lua_impl.intent.attack = false;
+                lua_impl.intent.attack = None;
             }
-            GameEvent::CharacterDied(_) => {}
+            GameEvent::CharacterDied(deceased) => {
+                if let Some(killer) = killer(deceased, events) {
+                    if !killer.allied_with(deceased) {
+                        game.character_state(killer).stats.kills += 1;
+                    }
+                }
+                let died_this_step = |meta: &character::Meta| {
+                    events
+                        .iter()
+                        .any(|event| matches!(event, GameEvent::CharacterDied(d) if d == meta))
+                };
+                for (meta, character_state) in game.characters.iter_mut() {
+                    if character_state.alive()
+                        && !died_this_step(meta)
+                        && !meta.allied_with(deceased)
+                    {
+                        character_state.stats.survival_points += 1;
+                    }
+                }
+            }
+            GameEvent::WallHit(_, _, _) => {}
+            GameEvent::ArenaShrunk(_) => {}
+            GameEvent::CharactersCollided(first, second, damage) => {
+                game.character_state(first).take_damage(*damage);
+                game.character_state(second).take_damage(*damage);
+            }
+            GameEvent::ArenaDamage(victim, damage) => {
+                game.character_state(victim).take_damage(*damage);
+            }
+            GameEvent::MessageSent(sender, _) => {
+                game.character(sender).intent.messages.clear();
+            }
+            GameEvent::CharacterDisqualified(meta) => {
+                game.character_state(meta).hp = 0.0;
+                game.character(meta).disqualified = true;
+            }
+            GameEvent::CharacterRemoved(meta) => {
+                game.character_state(meta).hp = 0.0;
+            }
+        }
+    }
+}
+
+/// The owner of the last attack that hit the deceased character in this step,
+/// if it was killed by an attack at all.
+fn killer<'a>(deceased: &character::Meta, events: &'a [GameEvent]) -> Option<&'a character::Meta> {
+    events.iter().rev().find_map(|event| match event {
+        GameEvent::Hit(_, owner, victim, _) if victim == deceased => Some(owner),
+        _ => None,
+    })
+}
+
+/// Disqualifies characters that skipped more turns than allowed or ran out of
+/// memory, and removes crashed ones from the round. They count as dead;
+/// disqualified characters also sit out all following rounds.
+fn remove_characters(game: &Game, event_manager: &mut EventManager) {
+    for (meta, _) in game.living_characters() {
+        let character = &game.impls[meta];
+        if let Some(reason) =
+            character.disqualification_reason(game.configuration.max_skipped_turns)
+        {
+            character::log_msg(&meta.display_name(), &format!("disqualified for {reason}"));
+            event_manager.record(GameEvent::CharacterDisqualified(meta.clone()));
+            event_manager.record(GameEvent::CharacterDied(meta.clone()));
+        } else if character.crashed {
+            character::log_msg(
+                &meta.display_name(),
+                "removed from the round after crashing",
+            );
+            event_manager.record(GameEvent::CharacterRemoved(meta.clone()));
+            event_manager.record(GameEvent::CharacterDied(meta.clone()));
         }
     }
 }
 
 fn check_for_round_end(game: &Game, event_manager: &mut EventManager) {
-    let ncharacters = game.living_characters().count();
-    match ncharacters {
-        0 => event_manager.record(GameEvent::RoundEnded(None)),
-        1 => event_manager.record(GameEvent::RoundEnded(Some(
-            game.living_characters().nth(0).unwrap().0.clone(),
-        ))),
+    let mut living = game.living_characters().map(|(meta, _)| meta);
+    match living.next() {
+        None => event_manager.record(GameEvent::RoundEnded(None)),
+        Some(first) if living.all(|meta| first.allied_with(meta)) => {
+            event_manager.record(GameEvent::RoundEnded(Some(Winner::from(first))))
+        }
+        _ if game.tick.0 >= game.configuration.max_ticks => match game.configuration.timeout_rule {
+            TimeoutRule::Draw => event_manager.record(GameEvent::RoundEnded(None)),
+            TimeoutRule::Hp => event_manager.record(GameEvent::RoundEnded(winner_by_hp(game))),
+            TimeoutRule::SuddenDeath => {}
+        },
         _ => {}
     }
 }
 
-fn run_characters(game: &mut Game, events: &[GameEvent]) -> Result<(), character::EventError> {
-    let character_positions: Vec<(character::Meta, Point)> = game
+/// The character or team with the most HP left, if there is exactly one. The
+/// HP of a team is the sum of its living members' HP.
+fn winner_by_hp(game: &Game) -> Option<Winner> {
+    let mut hp_by_side: BTreeMap<Winner, f32> = BTreeMap::new();
+    for (meta, character_state) in game.living_characters() {
+        *hp_by_side.entry(Winner::from(meta)).or_default() += character_state.hp;
+    }
+    let max_hp = hp_by_side.values().copied().fold(f32::MIN, f32::max);
+    let mut leaders = hp_by_side.into_iter().filter(|(_, hp)| *hp == max_hp);
+    match (leaders.next(), leaders.next()) {
+        (Some((winner, _)), None) => Some(winner),
+        _ => None,
+    }
+}
+
+fn run_characters(game: &mut Game, events: &[GameEvent]) {
+    let tick = game.tick.0;
+    let living: Vec<(character::Meta, character::State)> = game
         .living_characters()
-        .map(|(meta, p)| (meta.clone(), p.pos.clone()))
+        .map(|(meta, p)| (meta.clone(), p.clone()))
         .collect();
     for (meta, character_state) in game.characters.iter_mut() {
+        let character = &game.impls[meta];
+        if character.disqualified || character.crashed {
+            continue;
+        }
         let intent = &game.impls.get(meta).unwrap().intent;
-        let mut character_events =
-            game_events_to_character_events(meta, character_state, intent, events);
-        for (other_meta, pos) in character_positions.iter() {
+        let mut character_events = game_events_to_character_events(
+            meta,
+            character_state,
+            intent,
+            events,
+            game.configuration.message_scope,
+        );
+        for (other_meta, other_state) in living.iter() {
             if other_meta != meta {
                 if can_spot(
                     &character_state.pos,
                     character_state.effective_head_heading(),
-                    &pos,
-                    CHARACTER_RADIUS as f32,
-                    ANGLE_OF_VISION,
+                    &other_state.pos,
+                    game.rules.character_radius,
+                    game.rules.angle_of_vision,
+                    &game.obstacles,
                 ) {
-                    character_events.push(character::Event::EnemySeen(
-                        other_meta.name.clone(),
-                        pos.clone(),
-                    ));
+                    let seen = seen_character(character_state, other_meta, other_state, events);
+                    character_events.push(if meta.allied_with(other_meta) {
+                        character::Event::AllySeen(seen)
+                    } else {
+                        character::Event::EnemySeen(seen)
+                    });
                 }
             }
         }
+        character_events.append(&mut heard_sounds(
+            meta,
+            character_state,
+            &living,
+            events,
+            &game.rules,
+        ));
         let character = game.impls.get_mut(meta).unwrap();
-        let mut commands =
-            dispatch_character_events(character_events, &mut character.implementation)?;
+        if character.turn_skipped {
+            character_events.insert(0, character::Event::SkippedTurn);
+            character.turn_skipped = false;
+        }
+        let result = character.implementation.start_turn(tick).and_then(|_| {
+            dispatch_character_events(character_events, &mut character.implementation)
+        });
+        let mut commands = match result {
+            Ok(commands) => commands,
+            Err(e) => {
+                handle_character_error(
+                    meta,
+                    character,
+                    character_state,
+                    e,
+                    game.configuration.crash_policy,
+                );
+                continue;
+            }
+        };
         reduce_commands(&mut commands);
         for cmd in commands.iter() {
             match cmd {
-                character::Command::Attack => character.intent.attack = true,
+                character::Command::Attack(kind) => character.intent.attack = Some(*kind),
                 character::Command::Turn(angle) => character.intent.turn_angle = *angle,
                 character::Command::TurnHead(angle) => {
                     let current = character_state.head_heading;
-                    let next = clamp_turn_angle(current + *angle) - current;
+                    let next = clamp_turn_angle(current + *angle, &game.rules) - current;
                     character.intent.turn_head_angle = next;
                 }
                 character::Command::TurnArms(angle) => {
                     let current = character_state.arms_heading;
-                    let next = clamp_turn_angle(current + *angle) - current;
+                    let next = clamp_turn_angle(current + *angle, &game.rules) - current;
                     character.intent.turn_arms_angle = next;
                 }
                 character::Command::Move(dir, dist) => {
                     character.intent.direction = dir.clone();
                     character.intent.distance = *dist;
                 }
+                character::Command::Broadcast(message) => {
+                    let used: usize = character.intent.messages.iter().map(|m| m.size()).sum();
+                    if used + message.size() <= game.configuration.message_limit {
+                        character.intent.messages.push(message.clone());
+                    } else {
+                        character::log_msg(
+                            &meta.display_name(),
+                            "message limit for this tick exceeded, dropping message",
+                        );
+                    }
+                }
             }
         }
     }
-    Ok(())
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct StepEvents {
     pub events: Vec<GameEvent>,
 }
@@ -765,17 +1508,21 @@ pub fn step(
 ) -> Result<(), GameError> {
     event_manager.init_tick(game.tick);
     check_for_round_end(game, event_manager);
+    remove_characters(game, event_manager);
     transition_characters(game, event_manager);
     create_attacks(game, event_manager);
     transition_attacks(game, event_manager);
+    transition_arena(game, event_manager);
+    transition_messages(game, event_manager);
 
     let step_events: &StepEvents = &event_manager.current_events();
     advance_game_state(game, &step_events.events);
-    run_characters(game, &step_events.events)?;
+    run_characters(game, &step_events.events);
 
     if let Some(writer) = game_writer {
         writer.send(step_events.clone()).unwrap();
     }
+    event_manager.finish_step()?;
     Ok(())
 }
 
@@ -787,7 +1534,7 @@ pub fn run_round(
     game_writer: &mpsc::Sender<StepEvents>,
     cancel: &Arc<AtomicBool>,
 ) -> Result<(), GameError> {
-    game.init_round(round, event_manager);
+    game.init_round(round, event_manager)?;
     loop {
         if cancel.load(Ordering::Relaxed) {
             break;
@@ -797,12 +1544,8 @@ pub fn run_round(
         step(game, event_manager, Some(game_writer))?;
         match game.round_state {
             RoundState::Ongoing => {}
-            RoundState::Won(ref meta) => {
-                println!(
-                    "Character {} (ID {}) has won!",
-                    meta.display_name(),
-                    meta.id
-                );
+            RoundState::Won(ref winner) => {
+                println!("{winner} has won!");
                 break;
             }
             RoundState::Draw => {
@@ -817,7 +1560,9 @@ pub fn run_round(
 #[derive(Debug)]
 pub enum GameError {
     AddCharacterError(AddCharacterError),
-    LuaCharacterEventError(character::EventError),
+    Recording(ReplayError),
+    Placement(PlacementError),
+    Map(LoadMapError),
 }
 
 impl fmt::Display for GameError {
@@ -826,8 +1571,14 @@ impl fmt::Display for GameError {
             GameError::AddCharacterError(inner) => {
                 write!(f, "Character could not be added: {inner}")
             }
-            GameError::LuaCharacterEventError(inner) => {
-                write!(f, "Communication with character failed: {inner}")
+            GameError::Recording(inner) => {
+                write!(f, "Replay could not be written: {inner}")
+            }
+            GameError::Placement(inner) => {
+                write!(f, "Round could not be started: {inner}")
+            }
+            GameError::Map(inner) => {
+                write!(f, "Map cannot be used: {inner}")
             }
         }
     }
@@ -839,9 +1590,21 @@ impl From<AddCharacterError> for GameError {
     }
 }
 
-impl From<character::EventError> for GameError {
-    fn from(err: character::EventError) -> Self {
-        GameError::LuaCharacterEventError(err)
+impl From<ReplayError> for GameError {
+    fn from(err: ReplayError) -> Self {
+        GameError::Recording(err)
+    }
+}
+
+impl From<LoadMapError> for GameError {
+    fn from(err: LoadMapError) -> Self {
+        GameError::Map(err)
+    }
+}
+
+impl From<PlacementError> for GameError {
+    fn from(err: PlacementError) -> Self {
+        GameError::Placement(err)
     }
 }
 
@@ -852,7 +1615,11 @@ pub fn run_game(
     game_writer: mpsc::Sender<StepEvents>,
     cancel: Arc<AtomicBool>,
 ) -> Result<(), GameError> {
-    let mut event_manager = EventManager::new(EventRemembrance::Forget);
+    let mut event_manager = EventManager::with_battle_configuration(
+        EventRemembrance::Forget,
+        &battle_configuration,
+        game,
+    )?;
     for round in 1..=battle_configuration.rounds {
         if cancel.load(Ordering::Relaxed) {
             println!("Game cancelled");
@@ -867,6 +1634,7 @@ pub fn run_game(
             &cancel,
         )?;
     }
+    event_manager.finish()?;
     println!("GAME OVER");
     game.print_stats();
     Ok(())
@@ -877,17 +1645,13 @@ pub fn run_round_headless(
     round: Round,
     event_manager: &mut EventManager,
 ) -> Result<(), GameError> {
-    game.init_round(round, event_manager);
+    game.init_round(round, event_manager)?;
     loop {
         step(game, event_manager, None)?;
         match game.round_state {
             RoundState::Ongoing => {}
-            RoundState::Won(ref meta) => {
-                println!(
-                    "Character {} (ID {}) has won!",
-                    meta.display_name(),
-                    meta.id
-                );
+            RoundState::Won(ref winner) => {
+                println!("{winner} has won!");
                 break;
             }
             RoundState::Draw => {
@@ -896,18 +1660,164 @@ pub fn run_round_headless(
             }
         }
     }
-    println!("GAME OVER");
-    game.print_stats();
     Ok(())
 }
 
+/// Runs a whole battle without any delay and returns every `StepEvents`
+/// produced along the way.
+pub fn simulate_game(
+    game: &mut Game,
+    battle_configuration: &BattleConfiguration,
+) -> Result<Vec<StepEvents>, GameError> {
+    let mut event_manager = EventManager::new(EventRemembrance::Remember);
+    for round in 1..=battle_configuration.rounds {
+        run_round_headless(game, Round(round), &mut event_manager)?;
+    }
+    Ok(event_manager.into_events())
+}
+
 pub fn run_game_headless(
     game: &mut Game,
     battle_configuration: BattleConfiguration,
 ) -> Result<(), GameError> {
-    let mut event_manager = EventManager::new(EventRemembrance::Forget);
+    let mut event_manager = EventManager::with_battle_configuration(
+        EventRemembrance::Forget,
+        &battle_configuration,
+        game,
+    )?;
     for round in 1..=battle_configuration.rounds {
         run_round_headless(game, Round(round), &mut event_manager)?;
     }
+    event_manager.finish()?;
+    println!("GAME OVER");
+    game.print_stats();
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::character::Id;
+    use crate::color::Color;
+
+    fn meta(name: &str, team: &str) -> character::Meta {
+        character::Meta {
+            id: Id(uuid::Uuid::now_v7()),
+            name: name.to_string(),
+            color: Color {
+                red: 0,
+                green: 0,
+                blue: 0,
+            },
+            version: "1.0".to_string(),
+            entrypoint: "main.lua".into(),
+            command: None,
+            instance: 1,
+            team: Some(team.to_string()),
+        }
+    }
+
+    fn state_at(x: f32, y: f32) -> character::State {
+        character::State {
+            pos: Point { x, y },
+            ..character::State::new(100.0)
+        }
+    }
+
+    mod run_characters {
+        use super::*;
+
+        /// Moves on its first turn and crashes on every later one
+        struct CrashingCharacter {
+            turns: u32,
+        }
+
+        impl character::Impl for CrashingCharacter {
+            fn start_turn(&mut self, _tick: u32) -> Result<(), character::EventError> {
+                self.turns += 1;
+                Ok(())
+            }
+
+            fn on_event(
+                &mut self,
+                _event: &character::Event,
+            ) -> Result<character::Commands, character::EventError> {
+                if self.turns > 1 {
+                    return Err(character::EventError {
+                        kind: character::EventErrorKind::Other,
+                        message: "oops".to_string(),
+                    });
+                }
+                Ok(vec![character::Command::Move(MovementDirection::Forward, 10.0)].into())
+            }
+        }
+
+        #[test]
+        fn crashed_characters_stop_moving() {
+            let configuration: BattleConfiguration =
+                serde_json::from_str("{\"rounds\":1,\"characters\":[]}").unwrap();
+            let mut game = Game::new(configuration, 1);
+            let kai = meta("Kai", "red");
+            game.characters.insert(kai.clone(), state_at(100.0, 100.0));
+            game.impls.insert(
+                kai.clone(),
+                Character::new(Box::new(CrashingCharacter { turns: 0 })),
+            );
+            let events = [GameEvent::Tick(Tick(0))];
+            run_characters(&mut game, &events);
+            assert_eq!(game.character(&kai).intent.distance, 10.0);
+            run_characters(&mut game, &events);
+            assert_eq!(game.character(&kai).intent.distance, 0.0);
+            assert_eq!(game.character_state(&kai).stats.crashes, 1);
+        }
+    }
+
+    mod heard_sounds {
+        use super::*;
+
+        fn sounds_made_by(source: &character::Meta) -> Vec<character::Event> {
+            let listener = meta("Kai", "red");
+            let living = vec![
+                (listener.clone(), state_at(100.0, 100.0)),
+                (source.clone(), state_at(200.0, 100.0)),
+            ];
+            let attack = Attack {
+                id: AttackIds::new().next(),
+                kind: AttackKind::Normal,
+                pos: Point { x: 200.0, y: 100.0 },
+                origin: Point { x: 200.0, y: 100.0 },
+                owner: source.clone(),
+                heading: 0.0,
+                velocity: 1.0,
+            };
+            let events = [
+                GameEvent::AttackCreated(source.clone(), attack),
+                GameEvent::CharacterPositionUpdated(
+                    source.clone(),
+                    Delta::new(Point { x: 1.0, y: 0.0 }),
+                ),
+            ];
+            heard_sounds(&listener, &living[0].1, &living, &events, &Rules::default())
+        }
+
+        #[test]
+        fn enemies_are_heard_attacking_and_moving() {
+            let sounds = sounds_made_by(&meta("Lloyd", "blue"));
+            assert!(
+                matches!(
+                    sounds[..],
+                    [
+                        character::Event::Sound(character::SoundKind::Attack, _),
+                        character::Event::Sound(character::SoundKind::Movement, _),
+                    ]
+                ),
+                "{sounds:?}"
+            );
+        }
+
+        #[test]
+        fn allies_are_not_heard() {
+            assert!(sounds_made_by(&meta("Lloyd", "red")).is_empty());
+        }
+    }
+}
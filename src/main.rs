@@ -4,21 +4,31 @@ use std::{
     time::Duration,
 };
 
-use clap::Parser;
+use clap::{CommandFactory, Parser};
 use game::*;
 use render::GameRenderer;
-use settings::*;
 
 mod character;
 mod color;
 mod config;
 mod game;
+mod map;
 mod math_utils;
+mod obstacle;
 mod render;
+mod replay;
 mod settings;
+#[cfg(test)]
+mod test_utils;
+mod verify;
 
 fn main() {
     let cli = config::Cli::parse();
+    if let Err(err) = cli.validate() {
+        config::Cli::command()
+            .error(clap::error::ErrorKind::ArgumentConflict, err)
+            .exit();
+    }
     match cli.mode {
         config::Mode::Battle {
             battle_configuration,
@@ -26,45 +36,83 @@ fn main() {
         } => {
             if headless {
                 // FIXME: get rid of unwraps
-                let mut game = Game::with_characters(&battle_configuration.characters).unwrap();
+                let mut game = Game::from_battle_configuration(&battle_configuration).unwrap();
                 let _ = run_game_headless(&mut game, battle_configuration).unwrap();
             } else {
-                with_gui(|writer, cancel| {
-                    let character_dirs = battle_configuration.characters.clone();
+                with_gui(&battle_configuration, |writer, cancel| {
                     let cancel = cancel.clone();
                     let battle_configuration = battle_configuration.clone();
                     std::thread::spawn(move || {
-                        let mut game = Game::with_characters(&character_dirs)?;
+                        let mut game = Game::from_battle_configuration(&battle_configuration)?;
                         let delay = Duration::from_millis(7);
                         run_game(&mut game, battle_configuration, &delay, writer, cancel)
                     })
                 });
             }
         }
-        config::Mode::Replay { recording } => with_gui(|writer, cancel| {
-            let recording = recording.clone();
-            let cancel = cancel.clone();
-            std::thread::spawn(move || {
-                let delay = Duration::from_millis(5);
-                run_replay(&recording, writer, &delay, cancel)
+        config::Mode::Replay { recording } => {
+            let replay = match replay::ReplayReader::open(&recording) {
+                Ok(replay) => replay,
+                Err(err) => {
+                    println!("Could not open replay: {err}");
+                    std::process::exit(1);
+                }
+            };
+            let battle_configuration = replay.header.battle_configuration.clone();
+            with_gui(&battle_configuration, |writer, cancel| {
+                let cancel = cancel.clone();
+                std::thread::spawn(move || {
+                    let delay = Duration::from_millis(5);
+                    run_replay(replay, &recording, writer, &delay, cancel)
+                })
             })
-        }),
+        }
+        config::Mode::Verify {
+            replay,
+            battle_configuration,
+        } => {
+            let result = match replay {
+                Some(replay) => verify::verify_replay(&replay),
+                None => verify::resimulate(battle_configuration),
+            };
+            match result {
+                Ok(None) => println!("No divergence found"),
+                Ok(Some(divergence)) => {
+                    println!("{divergence}");
+                    std::process::exit(1);
+                }
+                Err(err) => {
+                    println!("Verification failed: {err}");
+                    std::process::exit(2);
+                }
+            }
+        }
     };
 }
 
 fn run_replay(
+    replay: replay::ReplayReader,
     history_file: &Path,
     sender: mpsc::Sender<StepEvents>,
     delay: &Duration,
     cancel: Arc<AtomicBool>,
 ) -> Result<(), String> {
-    let _f = std::fs::File::open(history_file)
-        .map_err(|e| format!("Could not load {history_file:?}. Error: {e}"))?;
-    let steps: Vec<StepEvents> = vec![];
-    for step_events in steps {
+    let battle_configuration = &replay.header.battle_configuration;
+    let characters: Vec<String> = battle_configuration
+        .characters
+        .iter()
+        .map(|entry| entry.to_string())
+        .collect();
+    println!(
+        "Replaying {} rounds with characters {:?}",
+        battle_configuration.rounds, characters
+    );
+    for step_events in replay {
         if cancel.load(std::sync::atomic::Ordering::Relaxed) {
             break;
         }
+        let step_events =
+            step_events.map_err(|e| format!("Could not read {history_file:?}. Error: {e}"))?;
         sender
             .send(step_events)
             .expect("Failed sending step events");
@@ -73,23 +121,35 @@ fn run_replay(
     Ok(())
 }
 
-fn with_gui<F, Err>(run: F)
+fn with_gui<F, Err>(battle_configuration: &config::BattleConfiguration, run: F)
 where
-    F: Fn(mpsc::Sender<StepEvents>, &Arc<AtomicBool>) -> std::thread::JoinHandle<Result<(), Err>>,
+    F: FnOnce(
+        mpsc::Sender<StepEvents>,
+        &Arc<AtomicBool>,
+    ) -> std::thread::JoinHandle<Result<(), Err>>,
     Err: std::fmt::Debug,
 {
     let (game_writer, game_reader) = mpsc::channel();
     let cancel = Arc::new(AtomicBool::new(false));
     let game_thread = run(game_writer, &cancel);
 
+    let rules = battle_configuration.effective_rules();
     let (mut rl, thread) = raylib::init()
         .log_level(raylib::ffi::TraceLogLevel::LOG_WARNING)
-        .size(WIDTH, HEIGHT)
+        .size(rules.width, rules.height)
         .title("hello world")
         .msaa_4x()
         .build();
     rl.set_target_fps(120);
-    let mut renderer = GameRenderer::new(&game_reader);
+    let mut renderer = GameRenderer::new(
+        &game_reader,
+        rules,
+        battle_configuration.all_obstacles(),
+        battle_configuration
+            .map
+            .as_ref()
+            .map_or(vec![], |map| map.hazards.clone()),
+    );
     while !rl.window_should_close() && !game_thread.is_finished() {
         renderer.step(&mut rl, &thread);
     }
@@ -0,0 +1,250 @@
+use core::fmt;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::math_utils::Point;
+use crate::obstacle::Obstacle;
+use crate::settings::Rules;
+
+/// An area of the arena damaging every character touching it, once per tick.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Hazard {
+    pub area: Obstacle,
+    pub damage: f32,
+}
+
+/// The layout of an arena. The size overrides the one from the rules; if
+/// spawn points are given, characters start the rounds on them.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Map {
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    #[serde(default)]
+    pub spawn_points: Vec<Point>,
+    #[serde(default)]
+    pub blocked: Vec<Obstacle>,
+    #[serde(default)]
+    pub hazards: Vec<Hazard>,
+}
+
+#[derive(Debug)]
+pub struct LoadMapError(pub String);
+
+impl fmt::Display for LoadMapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Map {
+    fn from_toml_str(toml: &str) -> Result<Self, LoadMapError> {
+        let map: Map =
+            toml::from_str(toml).map_err(|e| LoadMapError(format!("Invalid map: {e}")))?;
+        map.validate()?;
+        Ok(map)
+    }
+
+    pub fn from_toml_file(path: &Path) -> Result<Self, LoadMapError> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| LoadMapError(format!("Could not load {path:?}. Error: {e}")))?;
+        Self::from_toml_str(&contents)
+    }
+
+    /// Used by `clap` to parse the `--map` argument.
+    pub fn parse_file_arg(path: &str) -> Result<Self, String> {
+        Self::from_toml_file(Path::new(path)).map_err(|e| e.0)
+    }
+
+    fn validate(&self) -> Result<(), LoadMapError> {
+        for size in [self.width, self.height].into_iter().flatten() {
+            if size <= 0 {
+                return Err(LoadMapError(format!("Invalid arena size {size}")));
+            }
+        }
+        let mut areas = self
+            .blocked
+            .iter()
+            .chain(self.hazards.iter().map(|h| &h.area));
+        if let Some(area) = areas.find(|area| !area.is_valid()) {
+            return Err(LoadMapError(format!(
+                "Invalid area {area:?}, sizes need to be positive"
+            )));
+        }
+        let width = self.width.map_or(f32::INFINITY, |w| w as f32);
+        let height = self.height.map_or(f32::INFINITY, |h| h as f32);
+        for p in self.spawn_points.iter() {
+            if p.x < 0.0 || p.x > width || p.y < 0.0 || p.y > height {
+                return Err(LoadMapError(format!(
+                    "Spawn point ({}, {}) is outside of the arena",
+                    p.x, p.y
+                )));
+            }
+            if self.blocked.iter().any(|o| o.contains(p)) {
+                return Err(LoadMapError(format!(
+                    "Spawn point ({}, {}) is inside a blocked region",
+                    p.x, p.y
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that characters fit on the spawn points, which depends on the
+    /// rules and on obstacles given besides the map.
+    pub fn validate_spawn_points(
+        &self,
+        rules: &Rules,
+        obstacles: &[Obstacle],
+    ) -> Result<(), LoadMapError> {
+        let radius = rules.character_radius;
+        for (i, p) in self.spawn_points.iter().enumerate() {
+            if p.x < radius
+                || p.x > rules.width as f32 - radius
+                || p.y < radius
+                || p.y > rules.height as f32 - radius
+            {
+                return Err(LoadMapError(format!(
+                    "Spawn point ({}, {}) is too close to the walls",
+                    p.x, p.y
+                )));
+            }
+            if obstacles.iter().any(|o| o.overlaps_circle(p, radius)) {
+                return Err(LoadMapError(format!(
+                    "Spawn point ({}, {}) is too close to a blocked region",
+                    p.x, p.y
+                )));
+            }
+            if let Some(q) = self.spawn_points[..i]
+                .iter()
+                .find(|q| p.dist(q) <= 2.0 * radius)
+            {
+                return Err(LoadMapError(format!(
+                    "Spawn points ({}, {}) and ({}, {}) are too close to each other",
+                    q.x, q.y, p.x, p.y
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod map {
+        use super::*;
+
+        #[test]
+        fn can_be_loaded_from_toml_string() {
+            let toml_str = "
+width = 800
+height = 600
+spawn_points = [{ x = 100, y = 100 }, { x = 700, y = 500 }]
+
+[[blocked]]
+rectangle = { x = 350, y = 0, width = 100, height = 400 }
+
+[[hazards]]
+area = { circle = { center = { x = 400, y = 500 }, radius = 50 } }
+damage = 0.5
+";
+            let map = Map::from_toml_str(toml_str).unwrap();
+            assert_eq!(map.width, Some(800));
+            assert_eq!(map.spawn_points.len(), 2);
+            assert_eq!(
+                map.blocked,
+                vec![Obstacle::Rectangle {
+                    x: 350.0,
+                    y: 0.0,
+                    width: 100.0,
+                    height: 400.0
+                }]
+            );
+            assert_eq!(map.hazards[0].damage, 0.5);
+        }
+
+        #[test]
+        fn everything_is_optional() {
+            assert_eq!(Map::from_toml_str("").unwrap(), Map::default());
+        }
+
+        #[test]
+        fn rejects_spawn_points_outside_of_the_arena() {
+            let toml_str = "
+width = 800
+spawn_points = [{ x = 900, y = 100 }]
+";
+            assert!(Map::from_toml_str(toml_str).is_err());
+        }
+
+        #[test]
+        fn rejects_blocked_spawn_points() {
+            let toml_str = "
+spawn_points = [{ x = 100, y = 100 }]
+blocked = [{ rectangle = { x = 50, y = 50, width = 100, height = 100 } }]
+";
+            assert!(Map::from_toml_str(toml_str).is_err());
+        }
+
+        #[test]
+        fn rejects_invalid_areas() {
+            for toml_str in [
+                "blocked = [{ circle = { center = { x = 100, y = 100 }, radius = 0 } }]",
+                "blocked = [{ rectangle = { x = 50, y = 50, width = -100, height = 100 } }]",
+                "hazards = [{ area = { rectangle = { x = 50, y = 50, width = 100, height = 0 } }, damage = 1 }]",
+            ] {
+                assert!(Map::from_toml_str(toml_str).is_err(), "{toml_str}");
+            }
+        }
+
+        fn spawn_points_error(spawn_points: &str) -> Option<LoadMapError> {
+            let toml_str = format!(
+                "
+width = 800
+height = 600
+spawn_points = {spawn_points}
+blocked = [{{ rectangle = {{ x = 350, y = 0, width = 100, height = 400 }} }}]
+"
+            );
+            let map = Map::from_toml_str(&toml_str).unwrap();
+            let rules = Rules {
+                width: 800,
+                height: 600,
+                ..Default::default()
+            };
+            map.validate_spawn_points(&rules, &map.blocked).err()
+        }
+
+        #[test]
+        fn spawn_points_need_room_for_a_character() {
+            assert!(spawn_points_error("[{ x = 100, y = 100 }, { x = 700, y = 500 }]").is_none());
+            // Too close to the walls
+            assert!(spawn_points_error("[{ x = 10, y = 100 }]").is_some());
+            assert!(spawn_points_error("[{ x = 100, y = 590 }]").is_some());
+            // Too close to the blocked region
+            assert!(spawn_points_error("[{ x = 340, y = 100 }]").is_some());
+            // Too close to each other
+            assert!(spawn_points_error("[{ x = 100, y = 100 }, { x = 130, y = 100 }]").is_some());
+        }
+
+        #[test]
+        fn bundled_maps_are_valid() {
+            for map in [
+                include_str!("../maps/pillars.toml"),
+                include_str!("../maps/corridor.toml"),
+            ] {
+                let map = Map::from_toml_str(map).unwrap();
+                let defaults = Rules::default();
+                let rules = Rules {
+                    width: map.width.unwrap_or(defaults.width),
+                    height: map.height.unwrap_or(defaults.height),
+                    ..defaults
+                };
+                assert!(map.validate_spawn_points(&rules, &map.blocked).is_ok());
+            }
+        }
+    }
+}
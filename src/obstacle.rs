@@ -0,0 +1,268 @@
+use core::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::math_utils::{self, Point};
+
+/// Static parts of the arena that characters can neither walk through, see
+/// through, nor shoot through.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Obstacle {
+    Circle {
+        center: Point,
+        radius: f32,
+    },
+    /// An axis-aligned rectangle, `x` and `y` being its top left corner
+    Rectangle {
+        x: f32,
+        y: f32,
+        width: f32,
+        height: f32,
+    },
+}
+
+impl Obstacle {
+    /// Whether all numbers are finite and the sizes positive
+    pub fn is_valid(&self) -> bool {
+        match self {
+            Obstacle::Circle { center, radius } => {
+                center.x.is_finite() && center.y.is_finite() && radius.is_finite() && *radius > 0.0
+            }
+            Obstacle::Rectangle {
+                x,
+                y,
+                width,
+                height,
+            } => {
+                [x, y, width, height].iter().all(|v| v.is_finite()) && *width > 0.0 && *height > 0.0
+            }
+        }
+    }
+
+    /// Whether a circle at `center` with the given radius overlaps the
+    /// obstacle. Merely touching it does not count.
+    pub fn overlaps_circle(&self, center: &Point, radius: f32) -> bool {
+        match self {
+            Obstacle::Circle {
+                center: own_center,
+                radius: own_radius,
+            } => own_center.dist(center) < own_radius + radius,
+            Obstacle::Rectangle {
+                x,
+                y,
+                width,
+                height,
+            } => {
+                let closest = Point {
+                    x: math_utils::clamp(center.x, *x, x + width),
+                    y: math_utils::clamp(center.y, *y, y + height),
+                };
+                closest.dist(center) < radius
+            }
+        }
+    }
+
+    pub fn contains(&self, p: &Point) -> bool {
+        match self {
+            Obstacle::Circle { center, radius } => center.dist(p) < *radius,
+            Obstacle::Rectangle {
+                x,
+                y,
+                width,
+                height,
+            } => p.x > *x && p.x < x + width && p.y > *y && p.y < y + height,
+        }
+    }
+
+    /// Whether the line segment between `from` and `to` passes through the
+    /// obstacle.
+    pub fn intersects_segment(&self, from: &Point, to: &Point) -> bool {
+        match self {
+            Obstacle::Circle { center, radius } => distance_to_segment(center, from, to) < *radius,
+            Obstacle::Rectangle {
+                x,
+                y,
+                width,
+                height,
+            } => {
+                // Clip the segment's parameter range against both slabs of the
+                // rectangle; if anything is left, the segment passes through it
+                let mut t_min: f32 = 0.0;
+                let mut t_max: f32 = 1.0;
+                for (start, delta, lower, upper) in [
+                    (from.x, to.x - from.x, *x, x + width),
+                    (from.y, to.y - from.y, *y, y + height),
+                ] {
+                    if delta == 0.0 {
+                        if start <= lower || start >= upper {
+                            return false;
+                        }
+                    } else {
+                        let t1 = (lower - start) / delta;
+                        let t2 = (upper - start) / delta;
+                        t_min = t_min.max(t1.min(t2));
+                        t_max = t_max.min(t1.max(t2));
+                    }
+                }
+                t_min < t_max
+            }
+        }
+    }
+}
+
+fn distance_to_segment(p: &Point, from: &Point, to: &Point) -> f32 {
+    let len_sqr = from.dist_sqr(to);
+    if len_sqr == 0.0 {
+        return p.dist(from);
+    }
+    let t = ((p.x - from.x) * (to.x - from.x) + (p.y - from.y) * (to.y - from.y)) / len_sqr;
+    let t = math_utils::clamp(t, 0.0, 1.0);
+    let closest = Point {
+        x: from.x + t * (to.x - from.x),
+        y: from.y + t * (to.y - from.y),
+    };
+    p.dist(&closest)
+}
+
+#[derive(Debug)]
+pub struct ParseObstacleError(pub String);
+
+impl fmt::Display for ParseObstacleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ParseObstacleError {}
+
+/// Parses `circle:x,y,radius` or `rect:x,y,width,height`.
+impl FromStr for Obstacle {
+    type Err = ParseObstacleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, values) = s.split_once(':').ok_or(ParseObstacleError(format!(
+            "expected 'circle:x,y,radius' or 'rect:x,y,width,height', got '{s}'"
+        )))?;
+        let values = values
+            .split(',')
+            .map(|v| v.trim().parse::<f32>())
+            .collect::<Result<Vec<f32>, _>>()
+            .map_err(|e| ParseObstacleError(format!("invalid number in '{s}': {e}")))?;
+        if values.iter().any(|v| !v.is_finite()) {
+            return Err(ParseObstacleError(format!("non-finite number in '{s}'")));
+        }
+        let obstacle = match (kind, &values[..]) {
+            ("circle", [x, y, radius]) => Some(Obstacle::Circle {
+                center: Point { x: *x, y: *y },
+                radius: *radius,
+            }),
+            ("rect", [x, y, width, height]) => Some(Obstacle::Rectangle {
+                x: *x,
+                y: *y,
+                width: *width,
+                height: *height,
+            }),
+            _ => None,
+        };
+        obstacle
+            .filter(Obstacle::is_valid)
+            .ok_or(ParseObstacleError(format!(
+            "expected 'circle:x,y,radius' or 'rect:x,y,width,height' with positive sizes, got '{s}'"
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn circle() -> Obstacle {
+        Obstacle::Circle {
+            center: Point { x: 100.0, y: 100.0 },
+            radius: 20.0,
+        }
+    }
+
+    fn rect() -> Obstacle {
+        Obstacle::Rectangle {
+            x: 100.0,
+            y: 100.0,
+            width: 50.0,
+            height: 20.0,
+        }
+    }
+
+    mod from_str {
+        use super::*;
+
+        #[test]
+        fn parses_circles() {
+            assert_eq!("circle:100,100,20".parse::<Obstacle>().unwrap(), circle());
+        }
+
+        #[test]
+        fn parses_rectangles() {
+            assert_eq!("rect:100,100,50,20".parse::<Obstacle>().unwrap(), rect());
+        }
+
+        #[test]
+        fn rejects_wrong_number_of_values() {
+            assert!("circle:100,100".parse::<Obstacle>().is_err());
+            assert!("rect:1,2,3".parse::<Obstacle>().is_err());
+        }
+
+        #[test]
+        fn rejects_unknown_shapes_and_bad_sizes() {
+            assert!("triangle:1,2,3".parse::<Obstacle>().is_err());
+            assert!("circle:1,2,-3".parse::<Obstacle>().is_err());
+            assert!("rect:1,2,3,NaN".parse::<Obstacle>().is_err());
+        }
+    }
+
+    mod overlaps_circle {
+        use super::*;
+
+        #[test]
+        fn circle() {
+            let obstacle = super::circle();
+            assert!(obstacle.overlaps_circle(&Point { x: 130.0, y: 100.0 }, 15.0));
+            assert!(!obstacle.overlaps_circle(&Point { x: 140.0, y: 100.0 }, 15.0));
+        }
+
+        #[test]
+        fn rectangle() {
+            let obstacle = rect();
+            assert!(obstacle.overlaps_circle(&Point { x: 125.0, y: 110.0 }, 1.0));
+            assert!(obstacle.overlaps_circle(&Point { x: 160.0, y: 110.0 }, 15.0));
+            assert!(!obstacle.overlaps_circle(&Point { x: 160.0, y: 110.0 }, 5.0));
+            // Near the corner, but outside of the radius
+            assert!(!obstacle.overlaps_circle(&Point { x: 160.0, y: 130.0 }, 14.0));
+        }
+    }
+
+    mod intersects_segment {
+        use super::*;
+
+        #[test]
+        fn circle() {
+            let obstacle = super::circle();
+            let from = Point { x: 0.0, y: 100.0 };
+            assert!(obstacle.intersects_segment(&from, &Point { x: 200.0, y: 110.0 }));
+            assert!(!obstacle.intersects_segment(&from, &Point { x: 200.0, y: 150.0 }));
+            assert!(!obstacle.intersects_segment(&from, &Point { x: 50.0, y: 100.0 }));
+        }
+
+        #[test]
+        fn rectangle() {
+            let obstacle = rect();
+            let from = Point { x: 0.0, y: 110.0 };
+            assert!(obstacle.intersects_segment(&from, &Point { x: 200.0, y: 110.0 }));
+            assert!(!obstacle.intersects_segment(&from, &Point { x: 90.0, y: 110.0 }));
+            assert!(!obstacle.intersects_segment(&from, &Point { x: 200.0, y: 300.0 }));
+            assert!(obstacle
+                .intersects_segment(&Point { x: 125.0, y: 0.0 }, &Point { x: 125.0, y: 200.0 }));
+        }
+    }
+}
@@ -4,8 +4,11 @@ use std::sync::mpsc::Receiver;
 use raylib::prelude::*;
 
 use crate::game::{GameEvent, StepEvents};
+use crate::map::Hazard;
 use crate::math_utils::Point;
-use crate::{character, math_utils, settings::*};
+use crate::obstacle::Obstacle;
+use crate::settings::Rules;
+use crate::{character, math_utils};
 
 const VISION_COLOR: Color = Color {
     r: 150,
@@ -47,12 +50,14 @@ impl CharacterData {
 
 struct GameData {
     characters: HashMap<character::Meta, CharacterData>,
+    safe_zone_margin: Option<f32>,
 }
 
 impl GameData {
     fn new() -> Self {
         Self {
             characters: HashMap::new(),
+            safe_zone_margin: None,
         }
     }
 
@@ -74,9 +79,9 @@ fn draw_line_in_direction(
     d.draw_line(x, y, x + dx.round() as i32, y - dy.round() as i32, color);
 }
 
-fn draw_character_vision(d: &mut RaylibDrawHandle, x: i32, y: i32, heading: f32) {
-    let vision_delta = ANGLE_OF_VISION / 2.0;
-    let side_len = (WIDTH + HEIGHT) as f32; // don't know whether this is smart or dumb...
+fn draw_character_vision(d: &mut RaylibDrawHandle, x: i32, y: i32, heading: f32, rules: &Rules) {
+    let vision_delta = rules.angle_of_vision / 2.0;
+    let side_len = (rules.width + rules.height) as f32; // don't know whether this is smart or dumb...
     let origin = Vector2::new(x as f32, y as f32);
     let left_angle = math_utils::normalize_absolute_angle(heading - vision_delta);
     let left = math_utils::line_endpoint(origin.x, origin.y, side_len, left_angle);
@@ -90,43 +95,22 @@ fn draw_character_vision(d: &mut RaylibDrawHandle, x: i32, y: i32, heading: f32)
     );
 }
 
-fn draw_character_arms(d: &mut RaylibDrawHandle, x: i32, y: i32, heading: f32) {
-    draw_line_in_direction(
-        d,
-        x,
-        y,
-        heading,
-        1.5 * CHARACTER_RADIUS as f32,
-        &Color::YELLOW,
-    );
+fn draw_character_arms(d: &mut RaylibDrawHandle, x: i32, y: i32, heading: f32, radius: f32) {
+    draw_line_in_direction(d, x, y, heading, 1.5 * radius, &Color::YELLOW);
 }
 
-fn draw_heading(d: &mut RaylibDrawHandle, x: i32, y: i32, heading: f32, color: &Color) {
-    draw_line_in_direction(d, x, y, heading, 1.6 * CHARACTER_RADIUS as f32, color);
-    draw_line_in_direction(
-        d,
-        x,
-        y,
-        heading + PI as f32,
-        1.2 * CHARACTER_RADIUS as f32,
-        color,
-    );
-    draw_line_in_direction(
-        d,
-        x,
-        y,
-        heading + PI as f32 / 2.0,
-        1.2 * CHARACTER_RADIUS as f32,
-        color,
-    );
-    draw_line_in_direction(
-        d,
-        x,
-        y,
-        heading - PI as f32 / 2.0,
-        1.2 * CHARACTER_RADIUS as f32,
-        color,
-    );
+fn draw_heading(
+    d: &mut RaylibDrawHandle,
+    x: i32,
+    y: i32,
+    heading: f32,
+    radius: f32,
+    color: &Color,
+) {
+    draw_line_in_direction(d, x, y, heading, 1.6 * radius, color);
+    draw_line_in_direction(d, x, y, heading + PI as f32, 1.2 * radius, color);
+    draw_line_in_direction(d, x, y, heading + PI as f32 / 2.0, 1.2 * radius, color);
+    draw_line_in_direction(d, x, y, heading - PI as f32 / 2.0, 1.2 * radius, color);
 }
 
 fn to_raylib_color(color: &crate::color::Color) -> Color {
@@ -138,51 +122,111 @@ fn to_raylib_color(color: &crate::color::Color) -> Color {
     }
 }
 
-fn draw_character_name<'a>(d: &mut RaylibDrawHandle, name: &str, x: i32, y: i32, font_size: i32) {
+fn draw_character_name(
+    d: &mut RaylibDrawHandle,
+    name: &str,
+    x: i32,
+    y: i32,
+    radius: f32,
+    font_size: i32,
+) {
     let w = d.measure_text(name, font_size);
     d.draw_text(
         name,
         x - w / 2,
-        y + CHARACTER_RADIUS as i32 + font_size,
+        y + radius as i32 + font_size,
         font_size,
         TEXT_COLOR,
     );
 }
 
-fn draw_character_body<'a>(d: &mut RaylibDrawHandle, x: i32, y: i32, color: &Color) {
-    d.draw_circle(x, y, CHARACTER_RADIUS as f32, color);
+fn draw_character_body(d: &mut RaylibDrawHandle, x: i32, y: i32, radius: f32, color: &Color) {
+    d.draw_circle(x, y, radius, color);
 }
 
-fn draw_character<'a>(d: &mut RaylibDrawHandle, character: &'a CharacterData) {
+fn draw_character(d: &mut RaylibDrawHandle, character: &CharacterData, rules: &Rules) {
     let x = character.x.round() as i32;
     let y = character.y.round() as i32;
-    draw_character_vision(d, x, y, character.heading + character.head_heading);
-    draw_character_arms(d, x, y, character.heading + character.arms_heading);
-    draw_heading(d, x, y, character.heading, &character.color);
-    draw_character_body(d, x, y, &character.color);
-    draw_character_name(d, &character.display_name, x, y, 18);
+    let radius = rules.character_radius;
+    draw_character_vision(d, x, y, character.heading + character.head_heading, rules);
+    draw_character_arms(d, x, y, character.heading + character.arms_heading, radius);
+    draw_heading(d, x, y, character.heading, radius, &character.color);
+    draw_character_body(d, x, y, radius, &character.color);
+    draw_character_name(d, &character.display_name, x, y, radius, 18);
 }
 
-fn draw_attack(d: &mut RaylibDrawHandle, attack: &Point) {
+fn draw_attack(d: &mut RaylibDrawHandle, attack: &Point, rules: &Rules) {
     let attack_color = Color::GOLDENROD;
     d.draw_circle(
         attack.x.round() as i32,
         attack.y.round() as i32,
-        ATTACK_RADIUS,
+        rules.attack_radius,
         attack_color,
     );
 }
 
+fn draw_safe_zone(d: &mut RaylibDrawHandle, margin: f32, rules: &Rules) {
+    let margin = margin.round() as i32;
+    d.draw_rectangle_lines(
+        margin,
+        margin,
+        rules.width - 2 * margin,
+        rules.height - 2 * margin,
+        Color::RED,
+    );
+}
+
+const HAZARD_COLOR: Color = Color {
+    r: 200,
+    g: 60,
+    b: 20,
+    a: 80,
+};
+
+fn draw_area(d: &mut RaylibDrawHandle, area: &Obstacle, color: Color) {
+    match area {
+        Obstacle::Circle { center, radius } => d.draw_circle(
+            center.x.round() as i32,
+            center.y.round() as i32,
+            *radius,
+            color,
+        ),
+        Obstacle::Rectangle {
+            x,
+            y,
+            width,
+            height,
+        } => d.draw_rectangle(
+            x.round() as i32,
+            y.round() as i32,
+            width.round() as i32,
+            height.round() as i32,
+            color,
+        ),
+    }
+}
+
 pub struct GameRenderer<'a> {
     event_stream: &'a Receiver<StepEvents>,
     state: GameData,
+    rules: Rules,
+    obstacles: Vec<Obstacle>,
+    hazards: Vec<Hazard>,
 }
 
 impl<'a> GameRenderer<'a> {
-    pub fn new(event_stream: &'a Receiver<StepEvents>) -> Self {
+    pub fn new(
+        event_stream: &'a Receiver<StepEvents>,
+        rules: Rules,
+        obstacles: Vec<Obstacle>,
+        hazards: Vec<Hazard>,
+    ) -> Self {
         Self {
             event_stream,
             state: GameData::new(),
+            rules,
+            obstacles,
+            hazards,
         }
     }
 
@@ -191,6 +235,7 @@ impl<'a> GameRenderer<'a> {
             GameEvent::Tick(_) => {}
             GameEvent::RoundStarted(_, characters) => {
                 self.state.characters = HashMap::new();
+                self.state.safe_zone_margin = None;
                 for (meta, pos) in characters.iter() {
                     self.state
                         .characters
@@ -216,12 +261,19 @@ impl<'a> GameRenderer<'a> {
                 character.arms_heading = character.arms_heading + delta;
             }
             GameEvent::Hit(_, _, _, _) => {}
-            GameEvent::AttackAdvanced(_, pos) => draw_attack(d, &pos),
+            GameEvent::AttackAdvanced(_, pos) => draw_attack(d, &pos, &self.rules),
             GameEvent::AttackMissed(_) => {}
-            GameEvent::AttackCreated(_, a) => draw_attack(d, &a.pos),
+            GameEvent::AttackCreated(_, a) => draw_attack(d, &a.pos, &self.rules),
             GameEvent::CharacterDied(meta) => {
                 self.state.characters.remove(&meta);
             }
+            GameEvent::ArenaShrunk(margin) => self.state.safe_zone_margin = Some(margin),
+            GameEvent::ArenaDamage(_, _) => {}
+            GameEvent::CharactersCollided(_, _, _) => {}
+            GameEvent::WallHit(_, _, _) => {}
+            GameEvent::MessageSent(_, _) => {}
+            GameEvent::CharacterDisqualified(_) => {}
+            GameEvent::CharacterRemoved(_) => {}
         }
     }
 
@@ -244,11 +296,20 @@ impl<'a> GameRenderer<'a> {
 
     fn draw_characters(&self, d: &mut RaylibDrawHandle) {
         for character in self.state.characters.values() {
-            draw_character(d, character);
+            draw_character(d, character, &self.rules);
         }
     }
 
     fn draw(&self, d: &mut RaylibDrawHandle) {
+        for hazard in self.hazards.iter() {
+            draw_area(d, &hazard.area, HAZARD_COLOR);
+        }
+        for obstacle in self.obstacles.iter() {
+            draw_area(d, obstacle, Color::DARKGRAY);
+        }
+        if let Some(margin) = self.state.safe_zone_margin {
+            draw_safe_zone(d, margin, &self.rules);
+        }
         self.draw_characters(d);
     }
 }
@@ -0,0 +1,176 @@
+use core::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Lines, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::BattleConfiguration;
+use crate::game::StepEvents;
+
+/// Bump this whenever the serialized shape of `Header` or `StepEvents`
+/// changes in an incompatible way.
+pub const FORMAT_VERSION: u32 = 4;
+
+/// The first line of every replay file. Each following line contains the
+/// `StepEvents` of exactly one call to `game::step`, encoded as JSON.
+///
+/// The battle configuration is stored alongside (with the seed filled in), so
+/// that a recorded battle can be simulated again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Header {
+    pub version: u32,
+    #[serde(flatten)]
+    pub battle_configuration: BattleConfiguration,
+}
+
+#[derive(Debug)]
+pub struct ReplayError(pub String);
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl From<std::io::Error> for ReplayError {
+    fn from(err: std::io::Error) -> Self {
+        Self(format!("{err}"))
+    }
+}
+
+impl From<serde_json::Error> for ReplayError {
+    fn from(err: serde_json::Error) -> Self {
+        Self(format!("{err}"))
+    }
+}
+
+pub struct ReplayWriter {
+    writer: BufWriter<File>,
+}
+
+impl ReplayWriter {
+    pub fn create(path: &Path, header: &Header) -> Result<Self, ReplayError> {
+        let file = File::create(path)
+            .map_err(|e| ReplayError(format!("Could not create {path:?}. Error: {e}")))?;
+        let mut res = Self {
+            writer: BufWriter::new(file),
+        };
+        res.write_line(header)?;
+        Ok(res)
+    }
+
+    fn write_line<T: Serialize>(&mut self, value: &T) -> Result<(), ReplayError> {
+        serde_json::to_writer(&mut self.writer, value)?;
+        self.writer.write_all(b"\n")?;
+        Ok(())
+    }
+
+    pub fn write(&mut self, step_events: &StepEvents) -> Result<(), ReplayError> {
+        self.write_line(step_events)
+    }
+
+    pub fn flush(&mut self) -> Result<(), ReplayError> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+pub struct ReplayReader {
+    pub header: Header,
+    lines: Lines<BufReader<File>>,
+}
+
+impl ReplayReader {
+    pub fn open(path: &Path) -> Result<Self, ReplayError> {
+        let file = File::open(path)
+            .map_err(|e| ReplayError(format!("Could not load {path:?}. Error: {e}")))?;
+        let mut lines = BufReader::new(file).lines();
+        let first_line = lines
+            .next()
+            .ok_or(ReplayError(format!("{path:?} is empty")))??;
+        let header = parse_header(&first_line)?;
+        Ok(Self { header, lines })
+    }
+}
+
+impl Iterator for ReplayReader {
+    type Item = Result<StepEvents, ReplayError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let line = self.lines.next()?;
+        Some(
+            line.map_err(ReplayError::from)
+                .and_then(|line| Ok(serde_json::from_str(&line)?)),
+        )
+    }
+}
+
+fn parse_header(line: &str) -> Result<Header, ReplayError> {
+    let header: Header = serde_json::from_str(line)
+        .map_err(|e| ReplayError(format!("Invalid replay header: {e}")))?;
+    if header.version != FORMAT_VERSION {
+        return Err(ReplayError(format!(
+            "Unsupported replay version {} (expected {FORMAT_VERSION})",
+            header.version
+        )));
+    }
+    Ok(header)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod header {
+        use super::*;
+
+        #[test]
+        fn can_be_parsed() {
+            let header = parse_header(&format!(
+                "{{\"version\":{FORMAT_VERSION},\"rounds\":3,\"characters\":[\"characters/kai\"]}}"
+            ))
+            .unwrap();
+            let battle_configuration = header.battle_configuration;
+            assert_eq!(battle_configuration.rounds, 3);
+            assert_eq!(
+                battle_configuration.characters,
+                vec!["characters/kai".parse().unwrap()]
+            );
+            assert_eq!(battle_configuration.seed, None);
+        }
+
+        #[test]
+        fn rejects_unknown_version() {
+            let res = parse_header("{\"version\":9000,\"rounds\":3,\"characters\":[]}");
+            assert!(res.is_err());
+        }
+    }
+
+    mod roundtrip {
+        use super::*;
+        use crate::game::GameEvent;
+        use crate::test_utils::TempDir;
+
+        #[test]
+        fn written_steps_can_be_read_back() {
+            let dir = TempDir::new();
+            let path = dir.path().join("battle.replay");
+            let header = parse_header(&format!(
+                "{{\"version\":{FORMAT_VERSION},\"rounds\":1,\"characters\":[],\"seed\":42}}"
+            ))
+            .unwrap();
+            let mut writer = ReplayWriter::create(&path, &header).unwrap();
+            writer
+                .write(&StepEvents::from_slice(&[GameEvent::RoundEnded(None)]))
+                .unwrap();
+            writer.flush().unwrap();
+
+            let reader = ReplayReader::open(&path).unwrap();
+            assert_eq!(reader.header.battle_configuration.seed, Some(42));
+            let steps: Vec<StepEvents> = reader.map(|s| s.unwrap()).collect();
+            assert_eq!(steps.len(), 1);
+            assert!(matches!(steps[0].events[..], [GameEvent::RoundEnded(None)]));
+        }
+    }
+}
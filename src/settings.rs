@@ -1,15 +1,275 @@
+use core::fmt;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::character::AttackKind;
 use crate::math_utils::HALF_PI;
 
-pub const INITIAL_HP: f32 = 100.0;
-pub const MAX_TURN_RATE: f32 = 0.05;
-pub const MAX_HEAD_TURN_RATE: f32 = 0.1;
-pub const MAX_ARMS_TURN_RATE: f32 = 0.08;
-pub const ANGLE_OF_VISION: f32 = 0.9 * HALF_PI;
-pub const ANGLE_OF_ACTION: f32 = HALF_PI;
-pub const CHARACTER_RADIUS: f32 = 25.0;
-pub const ATTACK_RADIUS: f32 = 4.0;
-pub const ATTACK_DAMAGE: f32 = 10.0;
-pub const ATTACK_COOLDOWN: u8 = 35;
-pub const WIDTH: i32 = 1600;
-pub const HEIGHT: i32 = 1200;
-pub const MAX_VELOCITY: f32 = 1.0;
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AttackStats {
+    pub damage: f32,
+    pub velocity: f32,
+    /// How far the attack flies before it misses
+    pub range: f32,
+    /// The number of ticks until the next attack is possible
+    pub cooldown: u8,
+}
+
+/// The stats of every attack kind. Kinds missing from a `rules.toml` keep their
+/// default stats, but the ones that are given need to be complete.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AttackRules {
+    pub normal: AttackStats,
+    pub fast: AttackStats,
+    pub heavy: AttackStats,
+    pub melee: AttackStats,
+}
+
+impl AttackRules {
+    pub fn get(&self, kind: AttackKind) -> &AttackStats {
+        match kind {
+            AttackKind::Normal => &self.normal,
+            AttackKind::Fast => &self.fast,
+            AttackKind::Heavy => &self.heavy,
+            AttackKind::Melee => &self.melee,
+        }
+    }
+}
+
+impl Default for AttackRules {
+    fn default() -> Self {
+        Self {
+            normal: AttackStats {
+                damage: 10.0,
+                velocity: 2.5,
+                range: 10_000.0,
+                cooldown: 35,
+            },
+            fast: AttackStats {
+                damage: 4.0,
+                velocity: 5.0,
+                range: 10_000.0,
+                cooldown: 20,
+            },
+            heavy: AttackStats {
+                damage: 25.0,
+                velocity: 1.5,
+                range: 10_000.0,
+                cooldown: 80,
+            },
+            melee: AttackStats {
+                damage: 15.0,
+                velocity: 3.0,
+                range: 60.0,
+                cooldown: 25,
+            },
+        }
+    }
+}
+
+/// How much each of a character's stats counts towards its score
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScoreWeights {
+    pub round_won: f32,
+    pub damage_dealt: f32,
+    pub damage_taken: f32,
+    pub kill: f32,
+    pub survival: f32,
+    pub tick_survived: f32,
+    /// Counts the share of shots that hit an enemy, between 0 and 1
+    pub accuracy: f32,
+}
+
+impl Default for ScoreWeights {
+    fn default() -> Self {
+        Self {
+            round_won: 10.0,
+            damage_dealt: 1.0,
+            damage_taken: 0.0,
+            kill: 20.0,
+            survival: 50.0,
+            tick_survived: 0.0,
+            accuracy: 0.0,
+        }
+    }
+}
+
+/// All the tunable numbers of a battle. Every field is optional in a
+/// `rules.toml`, missing ones fall back to the default rules.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rules {
+    pub initial_hp: f32,
+    pub max_turn_rate: f32,
+    pub max_head_turn_rate: f32,
+    pub max_arms_turn_rate: f32,
+    pub angle_of_vision: f32,
+    pub angle_of_action: f32,
+    /// How far away attacking and moving enemies can be heard
+    pub hearing_radius: f32,
+    /// Bearings of sounds are rounded to multiples of this angle
+    pub hearing_precision: f32,
+    pub character_radius: f32,
+    pub attack_radius: f32,
+    pub width: i32,
+    pub height: i32,
+    pub max_velocity: f32,
+    pub sudden_death_shrink_rate: f32,
+    pub sudden_death_damage: f32,
+    pub attacks: AttackRules,
+    pub score: ScoreWeights,
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        Self {
+            initial_hp: 100.0,
+            max_turn_rate: 0.05,
+            max_head_turn_rate: 0.1,
+            max_arms_turn_rate: 0.08,
+            angle_of_vision: 0.9 * HALF_PI,
+            angle_of_action: HALF_PI,
+            hearing_radius: 400.0,
+            hearing_precision: HALF_PI / 2.0,
+            character_radius: 25.0,
+            attack_radius: 4.0,
+            width: 1600,
+            height: 1200,
+            max_velocity: 1.0,
+            sudden_death_shrink_rate: 0.5,
+            sudden_death_damage: 0.2,
+            attacks: AttackRules::default(),
+            score: ScoreWeights::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct LoadRulesError(pub String);
+
+impl fmt::Display for LoadRulesError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Rules {
+    fn from_toml_str(toml: &str) -> Result<Self, LoadRulesError> {
+        let rules: Rules =
+            toml::from_str(toml).map_err(|e| LoadRulesError(format!("Invalid rules: {e}")))?;
+        rules.validate()?;
+        Ok(rules)
+    }
+
+    pub fn from_toml_file(path: &Path) -> Result<Self, LoadRulesError> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| LoadRulesError(format!("Could not load {path:?}. Error: {e}")))?;
+        Self::from_toml_str(&contents)
+    }
+
+    /// Used by `clap` to parse the `--rules` argument.
+    pub fn parse_file_arg(path: &str) -> Result<Self, String> {
+        Self::from_toml_file(Path::new(path)).map_err(|e| e.0)
+    }
+
+    /// Rejects the values the game cannot work with, such as an arena too
+    /// small for a single character.
+    fn validate(&self) -> Result<(), LoadRulesError> {
+        let invalid = |what: &str| Err(LoadRulesError(format!("Invalid rules: {what}")));
+        if self.character_radius <= 0.0 {
+            return invalid("character_radius must be positive");
+        }
+        if self.hearing_precision <= 0.0 {
+            return invalid("hearing_precision must be positive");
+        }
+        let diameter = 2.0 * self.character_radius;
+        if self.width as f32 <= diameter || self.height as f32 <= diameter {
+            return invalid("width and height must be larger than a character");
+        }
+        for kind in [
+            AttackKind::Normal,
+            AttackKind::Fast,
+            AttackKind::Heavy,
+            AttackKind::Melee,
+        ] {
+            let stats = self.attacks.get(kind);
+            if stats.damage < 0.0 || stats.velocity < 0.0 || stats.range < 0.0 {
+                return invalid(&format!("{kind:?} attack stats must not be negative"));
+            }
+        }
+        // Rewarding missed shots would make no sense
+        if self.score.accuracy < 0.0 {
+            return invalid("the accuracy weight must not be negative");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod rules {
+        use super::*;
+
+        #[test]
+        fn missing_values_fall_back_to_defaults() {
+            let rules = Rules::from_toml_str("max_velocity = 2.0\nwidth = 800\n").unwrap();
+            assert_eq!(rules.max_velocity, 2.0);
+            assert_eq!(rules.width, 800);
+            assert_eq!(rules.height, Rules::default().height);
+            assert_eq!(rules.initial_hp, Rules::default().initial_hp);
+        }
+
+        #[test]
+        fn attack_kinds_can_be_overridden_separately() {
+            let toml_str = "
+[attacks.heavy]
+damage = 50.0
+velocity = 1.0
+range = 500.0
+cooldown = 100
+";
+            let rules = Rules::from_toml_str(toml_str).unwrap();
+            assert_eq!(rules.attacks.get(AttackKind::Heavy).damage, 50.0);
+            assert_eq!(rules.attacks.normal, AttackRules::default().normal);
+        }
+
+        #[test]
+        fn incomplete_attack_kinds_are_rejected() {
+            assert!(Rules::from_toml_str("[attacks.fast]\ndamage = 1.0").is_err());
+        }
+
+        #[test]
+        fn empty_file_gives_default_rules() {
+            assert_eq!(Rules::from_toml_str("").unwrap(), Rules::default());
+        }
+
+        #[test]
+        fn unknown_keys_are_rejected() {
+            assert!(Rules::from_toml_str("max_velocityy = 2.0").is_err());
+        }
+
+        #[test]
+        fn unusable_values_are_rejected() {
+            for toml_str in [
+                "character_radius = 0.0",
+                "character_radius = -5.0",
+                "hearing_precision = 0.0",
+                "[score]\naccuracy = -1.0",
+                "width = 50",
+                "height = -1",
+                "character_radius = 700.0",
+                "[attacks.melee]\ndamage = -1.0\nvelocity = 3.0\nrange = 60.0\ncooldown = 25",
+                "[attacks.fast]\ndamage = 1.0\nvelocity = -3.0\nrange = 60.0\ncooldown = 25",
+                "[attacks.heavy]\ndamage = 1.0\nvelocity = 3.0\nrange = -60.0\ncooldown = 25",
+            ] {
+                assert!(Rules::from_toml_str(toml_str).is_err(), "{toml_str}");
+            }
+        }
+    }
+}
@@ -0,0 +1,161 @@
+use core::fmt;
+use std::path::Path;
+
+use crate::config::BattleConfiguration;
+use crate::game::{self, Game, GameError, GameEvent, StepEvents};
+use crate::replay::{ReplayError, ReplayReader};
+
+/// The first event in which two event streams differ. One of `expected` and
+/// `actual` may be `None` if one of the streams ended early.
+#[derive(Debug)]
+pub struct Divergence {
+    pub round: Option<u16>,
+    pub tick: Option<u32>,
+    pub step: usize,
+    pub event_index: usize,
+    pub expected: Option<GameEvent>,
+    pub actual: Option<GameEvent>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn show<T: fmt::Debug>(x: &Option<T>) -> String {
+            x.as_ref()
+                .map_or("<none>".to_string(), |x| format!("{x:?}"))
+        }
+        writeln!(
+            f,
+            "Divergence in round {}, tick {} (step {}, event {}):",
+            show(&self.round),
+            show(&self.tick),
+            self.step,
+            self.event_index
+        )?;
+        writeln!(f, "  expected: {}", show(&self.expected))?;
+        write!(f, "  actual:   {}", show(&self.actual))
+    }
+}
+
+pub fn first_divergence(expected: &[StepEvents], actual: &[StepEvents]) -> Option<Divergence> {
+    let mut round = None;
+    let mut tick = None;
+    for step in 0..usize::max(expected.len(), actual.len()) {
+        let expected_events = expected.get(step).map_or(&[][..], |s| &s.events[..]);
+        let actual_events = actual.get(step).map_or(&[][..], |s| &s.events[..]);
+        for event_index in 0..usize::max(expected_events.len(), actual_events.len()) {
+            let expected_event = expected_events.get(event_index);
+            let actual_event = actual_events.get(event_index);
+            match expected_event.or(actual_event) {
+                Some(GameEvent::RoundStarted(r, _)) => round = Some(r.0),
+                Some(GameEvent::Tick(t)) => tick = Some(t.0),
+                _ => {}
+            }
+            if expected_event != actual_event {
+                return Some(Divergence {
+                    round,
+                    tick,
+                    step,
+                    event_index,
+                    expected: expected_event.cloned(),
+                    actual: actual_event.cloned(),
+                });
+            }
+        }
+    }
+    None
+}
+
+/// Characters get no data directory: what one run writes there would change
+/// what the next one reads, and the data a replay started with is long gone.
+fn simulate(battle_configuration: &BattleConfiguration) -> Result<Vec<StepEvents>, GameError> {
+    let battle_configuration = BattleConfiguration {
+        data_dir: None,
+        ..battle_configuration.clone()
+    };
+    let mut game = Game::from_battle_configuration(&battle_configuration)?;
+    game::simulate_game(&mut game, &battle_configuration)
+}
+
+/// Run the configured battle twice with the same seed and compare the results.
+pub fn resimulate(
+    mut battle_configuration: BattleConfiguration,
+) -> Result<Option<Divergence>, GameError> {
+    let seed = *battle_configuration.seed.get_or_insert_with(rand::random);
+    println!("Verifying with seed {seed}");
+    let expected = simulate(&battle_configuration)?;
+    let actual = simulate(&battle_configuration)?;
+    Ok(first_divergence(&expected, &actual))
+}
+
+/// Simulate the battle stored in a replay file again, and compare the result
+/// with the recorded events.
+pub fn verify_replay(path: &Path) -> Result<Option<Divergence>, GameError> {
+    let replay = ReplayReader::open(path)?;
+    let battle_configuration = replay.header.battle_configuration.clone();
+    if battle_configuration.seed.is_none() {
+        return Err(ReplayError(format!("{path:?} does not contain a seed")).into());
+    }
+    let expected = replay.collect::<Result<Vec<StepEvents>, ReplayError>>()?;
+    let actual = simulate(&battle_configuration)?;
+    Ok(first_divergence(&expected, &actual))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::{Round, Tick};
+
+    fn steps(events: &[&[GameEvent]]) -> Vec<StepEvents> {
+        events.iter().map(|e| StepEvents::from_slice(e)).collect()
+    }
+
+    mod first_divergence {
+        use super::*;
+
+        #[test]
+        fn identical_streams_do_not_diverge() {
+            let events = steps(&[
+                &[
+                    GameEvent::RoundStarted(Round(1), vec![]),
+                    GameEvent::Tick(Tick(0)),
+                ],
+                &[GameEvent::Tick(Tick(1)), GameEvent::RoundEnded(None)],
+            ]);
+            assert!(first_divergence(&events, &events.clone()).is_none());
+        }
+
+        #[test]
+        fn reports_round_and_tick_of_first_difference() {
+            let expected = steps(&[
+                &[
+                    GameEvent::RoundStarted(Round(2), vec![]),
+                    GameEvent::Tick(Tick(0)),
+                ],
+                &[GameEvent::Tick(Tick(1)), GameEvent::RoundEnded(None)],
+            ]);
+            let actual = steps(&[
+                &[
+                    GameEvent::RoundStarted(Round(2), vec![]),
+                    GameEvent::Tick(Tick(0)),
+                ],
+                &[GameEvent::Tick(Tick(1))],
+            ]);
+            let divergence = first_divergence(&expected, &actual).unwrap();
+            assert_eq!(divergence.round, Some(2));
+            assert_eq!(divergence.tick, Some(1));
+            assert_eq!(divergence.step, 1);
+            assert_eq!(divergence.event_index, 1);
+            assert_eq!(divergence.expected, Some(GameEvent::RoundEnded(None)));
+            assert_eq!(divergence.actual, None);
+        }
+
+        #[test]
+        fn missing_steps_are_a_divergence() {
+            let expected = steps(&[&[GameEvent::Tick(Tick(0))], &[GameEvent::Tick(Tick(1))]]);
+            let actual = steps(&[&[GameEvent::Tick(Tick(0))]]);
+            let divergence = first_divergence(&expected, &actual).unwrap();
+            assert_eq!(divergence.step, 1);
+            assert_eq!(divergence.actual, None);
+        }
+    }
+}